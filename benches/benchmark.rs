@@ -2,15 +2,19 @@
 extern crate crossbeam;
 // #![allow(unused_variables)]
 use std::collections::HashMap;
-use std::path::PathBuf;
 use std::thread;
 
 use criterion::measurement::WallTime;
-use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkGroup, Criterion};
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion,
+};
 use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    get_engine_by_name, Durability, KvStore, KvStoreOptions, KvsEngine, KvsEngineFactory,
+    SledKvsEngine,
+};
 use kvs::{utils::*, IKvsServer, KvsServer};
-use kvs::{Command, KvsClient, Result};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{Command, KvsClient};
 use rand::prelude::*;
 use rand::{rngs::SmallRng, SeedableRng};
 use tempfile::TempDir;
@@ -35,35 +39,6 @@ fn get_random_ascii_string_by_rng(rng: &mut SmallRng, size: usize) -> String {
         .collect::<String>()
 }
 
-enum EngineEnum {
-    KvStore(KvStore),
-    SledKvsEngine(SledKvsEngine),
-}
-
-impl EngineEnum {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        match self {
-            EngineEnum::KvStore(engine) => engine.set(key, value),
-            EngineEnum::SledKvsEngine(engine) => engine.set(key, value),
-        }
-    }
-
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self {
-            EngineEnum::KvStore(engine) => engine.get(key),
-            EngineEnum::SledKvsEngine(engine) => engine.get(key),
-        }
-    }
-}
-
-fn get_engine_by_name(name: &str, path: impl Into<PathBuf>) -> EngineEnum {
-    match name {
-        "kvs" => EngineEnum::KvStore(KvStore::open(path).unwrap()),
-        "sled" => EngineEnum::SledKvsEngine(SledKvsEngine::open(path).unwrap()),
-        _ => panic!("Unknown engine name"),
-    }
-}
-
 // With the kvs/sled engine, write 100 values with random keys of length 1-100000 bytes and random values of length 1-100000 bytes.
 fn write_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("write_bench");
@@ -83,14 +58,14 @@ fn write_bench(c: &mut Criterion) {
                         kv_pair.push((k, v));
                     }
                     (
-                        get_engine_by_name(engine_name, temp_dir.path()),
+                        get_engine_by_name(engine_name, temp_dir.path()).unwrap(),
                         temp_dir,
                         kv_pair,
                     )
                 },
-                |(mut engine, _temp_dir, kv_pair)| {
+                |(engine, _temp_dir, kv_pair)| {
                     for (key, value) in kv_pair {
-                        engine.set(key, value).unwrap();
+                        engine.set(&key, &value).unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -110,7 +85,7 @@ fn read_bench(c: &mut Criterion) {
         let mut rng = SmallRng::seed_from_u64(0);
         group.bench_with_input(engine_name, engine_name, |b, engine_name| {
             let temp_dir = TempDir::new().unwrap();
-            let mut engine = get_engine_by_name(engine_name, temp_dir.path());
+            let engine = get_engine_by_name(engine_name, temp_dir.path()).unwrap();
             let mut kv_pair = HashMap::new();
             for _ in 0..100 {
                 let k = get_random_ascii_string_by_rng(&mut rng, 10);
@@ -118,17 +93,76 @@ fn read_bench(c: &mut Criterion) {
                 kv_pair.insert(k, v);
             }
             for (key, value) in kv_pair.iter() {
-                engine.set(key.clone(), value.clone()).unwrap();
+                engine.set(key, value).unwrap();
             }
             drop(engine);
-            let mut engine_new = get_engine_by_name(engine_name, temp_dir.path());
+            let engine_new = get_engine_by_name(engine_name, temp_dir.path()).unwrap();
             b.iter(move || {
                 for (key, value) in &kv_pair {
-                    assert_eq!(engine_new.get(key.to_string()).unwrap().unwrap(), *value);
+                    assert_eq!(engine_new.get(key).unwrap().unwrap(), *value);
                 }
             })
         });
     }
+    // the same reads once compaction has moved every value out of the active file,
+    // they are copied from the memory map of the merged file instead of read from the file
+    let mut rng = SmallRng::seed_from_u64(0);
+    group.bench_function("kvs_compacted", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        let mut kv_pair = HashMap::new();
+        for _ in 0..100 {
+            let k = get_random_ascii_string_by_rng(&mut rng, 10);
+            let v = get_random_ascii_string_by_rng(&mut rng, 10);
+            kv_pair.insert(k, v);
+        }
+        for (key, value) in kv_pair.iter() {
+            store.set(key, value).unwrap();
+        }
+        store.compact().unwrap();
+        drop(store);
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        b.iter(move || {
+            for (key, value) in &kv_pair {
+                assert_eq!(store.get(key).unwrap().unwrap(), *value);
+            }
+        })
+    });
+    group.finish();
+}
+
+// With the kvs engine syncing every write, write 1000 values split between 1-16 threads.
+// The writers waiting for the same append are committed together with a single fsync,
+// so the time should go down as writers are added.
+fn concurrent_write_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_write_bench");
+    group
+        .sample_size(10)
+        .measurement_time(std::time::Duration::from_secs(5));
+    for writers in [1, 2, 4, 8, 16] {
+        group.bench_with_input(
+            BenchmarkId::new("kvs_always", writers),
+            &writers,
+            |b, &writers| {
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvStoreOptions::new().durability(Durability::Always);
+                let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                b.iter(|| {
+                    crossbeam::scope(|scope| {
+                        for writer in 0..writers {
+                            let store = store.clone();
+                            scope.spawn(move |_| {
+                                for i in 0..1000 / writers {
+                                    store.set(&format!("key{}:{}", writer, i), "value").unwrap();
+                                }
+                            });
+                        }
+                    })
+                    .unwrap();
+                })
+            },
+        );
+    }
     group.finish();
 }
 
@@ -151,7 +185,10 @@ fn run_write_bench(g: &mut BenchmarkGroup<WallTime>, name: &str) {
         b.iter(|| {
             for (key, value) in &kv_pair {
                 client
-                    .send(&Command::Set(key.clone(), value.clone()))
+                    .send(&Command::Set(
+                        key.clone().into_bytes(),
+                        value.clone().into_bytes(),
+                    ))
                     .unwrap();
             }
         })
@@ -170,13 +207,19 @@ fn run_read_bench(g: &mut BenchmarkGroup<WallTime>, name: &str) {
         }
         for (key, value) in &kv_pair {
             client
-                .send(&Command::Set(key.clone(), value.clone()))
+                .send(&Command::Set(
+                    key.clone().into_bytes(),
+                    value.clone().into_bytes(),
+                ))
                 .unwrap();
         }
         b.iter(|| {
             for (key, value) in &kv_pair {
-                let result = client.send(&Command::Get(key.clone())).unwrap().unwrap();
-                assert_eq!(result, value.clone());
+                let result = client
+                    .send(&Command::Get(key.clone().into_bytes()))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(result, value.as_bytes());
             }
         })
     });
@@ -196,7 +239,7 @@ macro_rules! write_queued_kvstore_with_config_string {
         let temp_dir = TempDir::new().unwrap();
         let server = KvsServer::new(
             ip_port,
-            <$engine_conf as KvsEngine>::open(temp_dir.path()).unwrap(),
+            <$engine_conf as KvsEngineFactory>::open(temp_dir.path()).unwrap(),
             <$pool_conf as ThreadPool>::new(num_thread).unwrap(),
             logger,
         )
@@ -300,5 +343,11 @@ fn read_queued_kvstore(c: &mut Criterion) {
 // but even using rwlock did not imporve read performance, maybe there are some bug need to fix...
 // maybe client should use muti-thread to read/write
 
-criterion_group!(benches, read_queued_kvstore, write_queued_kvstore);
+criterion_group!(
+    benches,
+    read_bench,
+    read_queued_kvstore,
+    write_queued_kvstore,
+    concurrent_write_bench
+);
 criterion_main!(benches);
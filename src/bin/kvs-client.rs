@@ -1,10 +1,14 @@
 #![feature(backtrace)]
+use std::backtrace::Backtrace;
+use std::io::{self, Write};
+use std::time::Duration;
+
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
-use kvs::Command;
+use kvs::{Command, KvsError, ScanRange};
 
 use clap::Parser;
 use kvs::utils::*;
@@ -29,6 +33,18 @@ struct Config {
     value: Option<String>,
     #[clap(long("addr"), default_value = "127.0.0.1:4000")]
     addr: String,
+    /// scan only: return at most this number of pairs
+    #[clap(long("limit"))]
+    limit: Option<usize>,
+    /// scan only: scan the keys starting with the given key instead of a range
+    #[clap(long("prefix"))]
+    prefix: bool,
+    /// cas only: swap only if the key currently has this value, absent if not given
+    #[clap(long("expected"))]
+    expected: Option<String>,
+    /// set only: the key expires after this number of seconds
+    #[clap(long("ttl"))]
+    ttl: Option<u64>,
 }
 
 fn main() -> Result<()> {
@@ -38,12 +54,81 @@ fn main() -> Result<()> {
     info!(root_logger, "Parse config successfully"; "config" => format!("{:?}", config));
     let ip_port = parse_ip_port(&config.addr)?;
 
-    let command: Command = CommandResult::from((config.command, config.key, config.value)).0?;
+    if config.command == "scan" {
+        // kvs-client scan <start> [end] or kvs-client scan <prefix> --prefix
+        let range = match (config.prefix, config.value) {
+            (true, None) => ScanRange::Prefix(config.key.into_bytes()),
+            (false, end) => ScanRange::Range(config.key.into_bytes(), end.map(String::into_bytes)),
+            (true, Some(end)) => {
+                return Err(KvsError::UnexpectedCommand {
+                    command: format!("scan --prefix {:?} {:?}", config.key, end),
+                    backtrace: Backtrace::force_capture(),
+                })
+            }
+        };
+        let pairs = KvsClient::new(ip_port, root_logger)?.scan(range, config.limit)?;
+        for (key, value) in pairs {
+            print_line(&[&key, b"\t", &value])?;
+        }
+        return Ok(());
+    }
+    if config.command == "cas" {
+        // kvs-client cas <key> [new] [--expected <value>], a missing value removes the key
+        let swapped = KvsClient::new(ip_port, root_logger)?.compare_and_swap(
+            config.key.clone().into_bytes(),
+            config.expected.map(String::into_bytes),
+            config.value.map(String::into_bytes),
+        )?;
+        if !swapped {
+            return Err(anyhow::anyhow!("Value mismatch: {}", config.key).into());
+        }
+        return Ok(());
+    }
+    if config.command == "ttl" {
+        // kvs-client ttl <key> prints the seconds left, rounded up
+        if let Some(value) = config.value {
+            return Err(KvsError::UnexpectedCommand {
+                command: format!("ttl {:?} {:?}", config.key, value),
+                backtrace: Backtrace::force_capture(),
+            });
+        }
+        match KvsClient::new(ip_port, root_logger)?.ttl(config.key.into_bytes())? {
+            Some(ttl) => println!("{}", ttl.as_secs_f64().ceil()),
+            None => println!("No expiry"),
+        }
+        return Ok(());
+    }
+    if config.limit.is_some()
+        || config.prefix
+        || config.expected.is_some()
+        || (config.ttl.is_some() && config.command != "set")
+    {
+        return Err(KvsError::UnexpectedCommand {
+            command: format!("{} --limit/--prefix/--expected/--ttl", config.command),
+            backtrace: Backtrace::force_capture(),
+        });
+    }
+
+    let mut command: Command = CommandResult::from((config.command, config.key, config.value)).0?;
+    if let (Command::Set(key, value), Some(ttl)) = (&command, config.ttl) {
+        command = Command::SetWithTtl(key.clone(), value.clone(), Duration::from_secs(ttl));
+    }
     let result = KvsClient::new(ip_port, root_logger)?.send(&command)?;
     if let Some(result) = result {
-        println!("{}", result);
+        print_line(&[&result])?;
     } else if let Command::Get(_) = command {
         println!("Key not found");
     }
     Ok(())
 }
+
+// values are printed as they are stored, they are not necessarily utf-8
+fn print_line(parts: &[&[u8]]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for part in parts {
+        stdout.write_all(part)?;
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
@@ -8,12 +8,13 @@ extern crate anyhow;
 use clap::Parser;
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
 use kvs::utils::*;
+use kvs::Durability;
 use kvs::IKvsServer;
-use kvs::KvStore;
-use kvs::KvsEngine;
 use kvs::KvsServer;
 use kvs::Result;
-use kvs::SledKvsEngine;
+use kvs::{get_engine_by_name, get_engine_with_durability, CachedEngine};
+use std::env::current_dir;
+use std::sync::Arc;
 
 #[derive(Parser, Debug)]
 #[clap(version = env!("CARGO_PKG_VERSION"), author = "QingGo")]
@@ -25,6 +26,13 @@ struct Config {
     addr: String,
     #[clap(long("engine"), value_name("ENGINE-NAME"))]
     engine: Option<String>,
+    /// When writes are synced to disk: always, every:<writes>, interval:<ms> or os,
+    /// the default of the engine if not given
+    #[clap(long("durability"), value_name("POLICY"))]
+    durability: Option<Durability>,
+    /// Keep up to this many bytes of the most recently read values in memory
+    #[clap(long("cache-size"), value_name("BYTES"))]
+    cache_size: Option<u64>,
 }
 
 fn main() -> Result<()> {
@@ -35,17 +43,26 @@ fn main() -> Result<()> {
     info!(root_logger, "Starting kvs-server"; "version" => env!("CARGO_PKG_VERSION"));
     let config = Config::parse();
     info!(root_logger, "Parse config successfully"; "config" => format!("{:?}", config));
-    let last_engine = get_last_engine();
-    let engine_name = get_engine(last_engine, config.engine)?;
+    let engine_name = get_engine(&current_dir()?, config.engine)?;
     let ip_port = parse_ip_port(&config.addr)?;
 
     let log = root_logger.new(o!("engine" => "kvs"));
     log::info!("engine_name: {}", engine_name);
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-    match engine_name.as_str() {
-        "kvs" => KvsServer::new(ip_port, KvStore::new()?, pool, log)?.run()?,
-        "sled" => KvsServer::new(ip_port, SledKvsEngine::new()?, pool, log)?.run()?,
-        _ => panic!("Unknown engine name"),
+    let engine = match config.durability {
+        Some(durability) => {
+            log::info!("durability: {}", durability);
+            get_engine_with_durability(&engine_name, current_dir()?, durability)?
+        }
+        None => get_engine_by_name(&engine_name, current_dir()?)?,
     };
+    let engine = match config.cache_size {
+        Some(cache_size) => {
+            log::info!("cache size: {} bytes", cache_size);
+            Arc::new(CachedEngine::new(engine, cache_size))
+        }
+        None => engine,
+    };
+    KvsServer::with_shared_engine(ip_port, engine, pool, log)?.run()?;
     Ok(())
 }
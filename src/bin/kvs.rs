@@ -1,8 +1,10 @@
 #![feature(backtrace)]
 use std::backtrace::Backtrace;
+use std::env::current_dir;
 
 use clap::Parser;
-use kvs::{get_engine_by_name, KvsError, Result};
+use kvs::utils::get_engine;
+use kvs::{get_engine_by_name, migrate, KvsError, Result};
 
 #[derive(Parser)]
 #[clap(version = env!("CARGO_PKG_VERSION"), author = "QingGo")]
@@ -10,16 +12,44 @@ struct Opts {
     #[clap(short('V'))]
     // 更改了默认 -v 的行为
     version: bool,
-    #[clap(short('e'), default_value("kvs"))]
-    engine_name: String,
+    /// the engine the directory was written by if not given, or kvs for a new one
+    #[clap(short('e'))]
+    engine_name: Option<String>,
     command: String,
     key: String,
     value: Option<String>,
+    /// migrate only: engine the keys are in
+    #[clap(long("from"))]
+    from: Option<String>,
+    /// migrate only: engine the keys are copied into
+    #[clap(long("to"))]
+    to: Option<String>,
 }
 
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
-    let db = get_engine_by_name(opts.engine_name.as_str());
+    if opts.command == "migrate" {
+        // kvs migrate <dir> --from <engine> --to <engine>
+        return match (&opts.from, &opts.to, &opts.value) {
+            (Some(from), Some(to), None) => {
+                let report = migrate(&opts.key, from, to)?;
+                println!(
+                    "{} keys migrated, checksum {:08x}",
+                    report.count, report.checksum
+                );
+                Ok(())
+            }
+            _ => Err(KvsError::UnexpectedCommand {
+                command: format!(
+                    "migrate {:?} --from {:?} --to {:?} {:?}",
+                    opts.key, opts.from, opts.to, opts.value
+                ),
+                backtrace: Backtrace::force_capture(),
+            }),
+        };
+    }
+    let engine_name = get_engine(&current_dir()?, opts.engine_name)?;
+    let db = get_engine_by_name(&engine_name, current_dir()?)?;
 
     match opts.command.as_str() {
         "get" => {
@@ -29,16 +59,16 @@ fn main() -> Result<()> {
                     backtrace: Backtrace::force_capture(),
                 });
             }
-            let record = db.get(opts.key)?;
+            let record = db.get(&opts.key)?;
             record
                 .map(|r| println!("{}", r))
                 .unwrap_or_else(|| println!("Key not found"));
         }
         "set" => {
-            db.set(opts.key, opts.value.unwrap())?;
+            db.set(&opts.key, &opts.value.unwrap())?;
         }
         "rm" => {
-            db.remove(opts.key).map_err(|err| {
+            db.remove(&opts.key).map_err(|err| {
                 // If the key does not exist, it prints "Key not found", and exits with a non-zero error code
                 if let KvsError::KeyNotFound {
                     key: _,
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::engine::{BatchOp, BytesScanIter, KvsEngine, KvsEngineFactory, WriteBatch};
+use super::error::{KvsError, Result};
+use super::snapshot::KvsSnapshot;
+
+/// Size of the cache of an engine opened with `KvsEngineFactory::open`, in bytes.
+pub const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Counters of a `CachedEngine`, and what its cache holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Reads answered from the cache
+    pub hits: u64,
+    /// Reads that went to the engine
+    pub misses: u64,
+    /// Values in the cache
+    pub entries: u64,
+    /// Bytes of the keys and values in the cache
+    pub size: u64,
+}
+
+/// A `KvsEngine` keeping the values last read from `E` in memory, up to a number of bytes,
+/// the least recently used ones are evicted first. It suits reads skewed toward a few hot keys.
+///
+/// Every write through the cache drops the values of the keys it writes, writes made to `E`
+/// without going through the cache are not seen. Absent keys are not cached, and a value set
+/// with a ttl is dropped once it expires.
+/// ```rust
+/// # use kvs::{CachedEngine, KvsEngine, MemoryKvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// let engine = CachedEngine::new(MemoryKvsEngine::default(), 1024 * 1024);
+/// engine.set("key1", "value1")?;
+/// assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+/// assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+/// assert_eq!((engine.stats().misses, engine.stats().hits), (1, 1));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CachedEngine<E> {
+    engine: E,
+    cache: Arc<Cache>,
+}
+
+struct Cache {
+    capacity: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    // keys by the tick of their last use, the first one is the next to evict
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    size: u64,
+    // bumped by every write, a value read from the engine before a write is not cached,
+    // it may be the value the write replaced
+    generation: u64,
+}
+
+struct CacheEntry {
+    value: Vec<u8>,
+    expire_at: Option<Instant>,
+    tick: u64,
+}
+
+impl<E: KvsEngine> CachedEngine<E> {
+    /// Cache up to `capacity` bytes of keys and values read from `engine`.
+    pub fn new(engine: E, capacity: u64) -> CachedEngine<E> {
+        CachedEngine {
+            engine,
+            cache: Arc::new(Cache {
+                capacity,
+                lru: Mutex::new(Lru::default()),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// The engine behind the cache.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Return the hit and miss counters since the cache was created, and its current content.
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.cache.lru.lock().unwrap();
+        CacheStats {
+            hits: self.cache.hits.load(Ordering::Relaxed),
+            misses: self.cache.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len() as u64,
+            size: lru.size,
+        }
+    }
+
+    /// Drop the cached values of `keys`, once the engine has written them.
+    fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) {
+        let mut lru = self.cache.lru.lock().unwrap();
+        for key in keys {
+            lru.remove(key);
+        }
+        lru.generation += 1;
+    }
+}
+
+impl<E: KvsEngineFactory> KvsEngineFactory for CachedEngine<E> {
+    /// Open the engine in `path`, with a cache of `DEFAULT_CACHE_SIZE` bytes.
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(CachedEngine::new(E::open(path)?, DEFAULT_CACHE_SIZE))
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachedEngine<E> {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let result = self.engine.set_bytes(key, value);
+        self.invalidate([key]);
+        result
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let result = self.engine.set_bytes_with_ttl(key, value, ttl);
+        self.invalidate([key]);
+        result
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.engine.ttl_bytes(key)
+    }
+
+    /// A miss also reads the ttl of the key from the engine, to drop the value once it expires.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let generation = {
+            let mut lru = self.cache.lru.lock().unwrap();
+            if let Some(value) = lru.get(key) {
+                self.cache.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            lru.generation
+        };
+        self.cache.misses.fetch_add(1, Ordering::Relaxed);
+        let value = match self.engine.get_bytes(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // taken before the ttl is read, so that the cached value expires no later than the key
+        let now = Instant::now();
+        let expire_at = match self.engine.ttl_bytes(key) {
+            Ok(ttl) => ttl.map(|ttl| now + ttl),
+            // expired or removed since it was read
+            Err(KvsError::KeyNotFound { .. }) => return Ok(Some(value)),
+            Err(e) => return Err(e),
+        };
+        let mut lru = self.cache.lru.lock().unwrap();
+        if lru.generation == generation {
+            lru.insert(key, value.clone(), expire_at, self.cache.capacity);
+        }
+        Ok(Some(value))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let result = self.engine.remove_bytes(key);
+        self.invalidate([key]);
+        result
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys: Vec<Vec<u8>> = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set(key, _) | BatchOp::Remove(key) => key.clone(),
+            })
+            .collect();
+        let result = self.engine.write_batch(batch);
+        self.invalidate(keys.iter().map(Vec::as_slice));
+        result
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let result = self.engine.compare_and_swap_bytes(key, expected, new);
+        if !matches!(result, Ok(false)) {
+            self.invalidate([key]);
+        }
+        result
+    }
+
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        self.engine.scan_bytes(start, end, limit)
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter> {
+        self.engine.scan_prefix_bytes(prefix)
+    }
+
+    /// The snapshot reads from the engine, the cached values are newer than it.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.engine.snapshot()
+    }
+}
+
+impl Lru {
+    /// Return the value of `key` and mark it as the most recently used, None if absent or expired.
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let expired = match self.entries.get(key)?.expire_at {
+            Some(expire_at) => expire_at <= Instant::now(),
+            None => false,
+        };
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let entry = self.entries.get_mut(key).unwrap();
+        self.order.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.to_vec());
+        Some(entry.value.clone())
+    }
+
+    /// Cache `value`, evicting the least recently used values until it fits in `capacity`.
+    fn insert(&mut self, key: &[u8], value: Vec<u8>, expire_at: Option<Instant>, capacity: u64) {
+        let size = entry_size(key, &value);
+        if size > capacity {
+            return;
+        }
+        self.remove(key);
+        while self.size + size > capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_vec());
+        self.entries.insert(
+            key.to_vec(),
+            CacheEntry {
+                value,
+                expire_at,
+                tick: self.tick,
+            },
+        );
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.size -= entry_size(key, &entry.value);
+        }
+    }
+}
+
+fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
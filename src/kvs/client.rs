@@ -1,13 +1,15 @@
 use super::error::Result;
-use super::protocol::{Command, Response};
+use super::protocol::{read_frame, write_frame, Command, Response, ScanRange};
 use anyhow::anyhow;
 use slog::Logger;
-use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
 use std::net::TcpStream;
+use std::time::Duration;
 
 pub struct KvsClient {
     logger: Logger,
-    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
 }
 
 impl KvsClient {
@@ -15,23 +17,64 @@ impl KvsClient {
         // let (ip, port) = ip_port;
         let stream = std::net::TcpStream::connect(ip_port)?;
         info!(logger, "connect to server"; "addr" => format!("{:?}", ip_port));
-        Ok(KvsClient { logger, stream })
+        let reader = BufReader::new(stream.try_clone()?);
+        let writer = BufWriter::new(stream);
+        Ok(KvsClient {
+            logger,
+            reader,
+            writer,
+        })
     }
 
-    pub fn send(&mut self, input: &Command) -> Result<Option<String>> {
-        let serialized_input = serde_json::to_string(&input)?;
-        debug!(self.logger, "send request"; "request" => &serialized_input);
-        self.stream.write_all(serialized_input.as_bytes())?;
-        let mut buf = vec![0; 1024];
-        let n = self.stream.read(&mut buf)?;
-        if n == 0 {
-            return Err(anyhow!("server closed").into());
-        }
-        let output: Response = serde_json::from_slice(&buf[..n])?;
-        debug!(self.logger, "recv response"; "response" => format!("{:?}", &output));
-        match output {
+    pub fn send(&mut self, input: &Command) -> Result<Option<Vec<u8>>> {
+        match self.request(input)? {
             Response::Success(result) => Ok(result),
             Response::Error(err) => Err(anyhow!(err).into()),
+            output => Err(anyhow!("unexpected response: {:?}", output).into()),
         }
     }
+
+    pub fn scan(
+        &mut self,
+        range: ScanRange,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.request(&Command::Scan(range, limit))? {
+            Response::Pairs(pairs) => Ok(pairs),
+            Response::Error(err) => Err(anyhow!(err).into()),
+            output => Err(anyhow!("unexpected response: {:?}", output).into()),
+        }
+    }
+
+    /// Return whether the value of `key` was `expected` and has been replaced with `new`.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self.request(&Command::CompareAndSwap(key, expected, new))? {
+            Response::Swapped(swapped) => Ok(swapped),
+            Response::Error(err) => Err(anyhow!(err).into()),
+            output => Err(anyhow!("unexpected response: {:?}", output).into()),
+        }
+    }
+
+    /// Return the time left before `key` expires, None if it never expires.
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.request(&Command::Ttl(key))? {
+            Response::Ttl(ttl) => Ok(ttl),
+            Response::Error(err) => Err(anyhow!(err).into()),
+            output => Err(anyhow!("unexpected response: {:?}", output).into()),
+        }
+    }
+
+    fn request(&mut self, input: &Command) -> Result<Response> {
+        debug!(self.logger, "send request"; "request" => format!("{:?}", input));
+        write_frame(&mut self.writer, input)?;
+        let output: Response =
+            read_frame(&mut self.reader)?.ok_or_else(|| anyhow!("server closed"))?;
+        debug!(self.logger, "recv response"; "response" => format!("{:?}", &output));
+        Ok(output)
+    }
 }
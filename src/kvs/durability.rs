@@ -0,0 +1,125 @@
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+
+use super::error::{KvsError, Result};
+
+/// When the writes acknowledged by an engine are forced to disk, shared by the engines keeping files.
+/// Each engine keeps its own default: KvStore and LsmKvsEngine leave their logs to the OS,
+/// SledKvsEngine flushes every write, since sled buffers them in the process itself.
+///
+/// Parsed from `always`, `every:<writes>`, `interval:<ms>` or `os`, as given to `kvs-server --durability`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync the log after every write, before acknowledging it
+    Always,
+    /// Sync the log once every `n` writes, the last `n - 1` writes can be lost
+    EveryN(u32),
+    /// Sync the log from a background thread every `ms` milliseconds,
+    /// the writes of the last interval can be lost
+    Interval(u64),
+    /// Never sync explicitly, the OS writes its buffers back when it sees fit
+    OsBuffered,
+}
+
+impl Durability {
+    /// Whether `unsynced` writes since the last sync call for one now.
+    pub(crate) fn needs_sync(self, unsynced: u64) -> bool {
+        match self {
+            Durability::Always => unsynced > 0,
+            Durability::EveryN(n) => unsynced >= u64::from(n.max(1)),
+            Durability::Interval(_) | Durability::OsBuffered => false,
+        }
+    }
+
+    /// Period of the background sync, for `Interval`.
+    pub(crate) fn interval(self) -> Option<Duration> {
+        match self {
+            Durability::Interval(ms) => Some(Duration::from_millis(ms.max(1))),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Durability> {
+        let invalid = || KvsError::InvalidDurability {
+            value: s.to_owned(),
+            backtrace: Backtrace::force_capture(),
+        };
+        match s.split_once(':') {
+            None if s == "always" => Ok(Durability::Always),
+            None if s == "os" => Ok(Durability::OsBuffered),
+            Some(("every", n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(Durability::EveryN(n)),
+                _ => Err(invalid()),
+            },
+            Some(("interval", ms)) => match ms.parse() {
+                Ok(ms) if ms > 0 => Ok(Durability::Interval(ms)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::EveryN(n) => write!(f, "every:{}", n),
+            Durability::Interval(ms) => write!(f, "interval:{}", ms),
+            Durability::OsBuffered => write!(f, "os"),
+        }
+    }
+}
+
+/// Owns the thread syncing an engine for `Durability::Interval`, dropping it syncs once more
+/// and waits for the thread.
+pub(crate) struct Syncer {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub(crate) fn spawn<F>(name: &str, interval: Duration, sync: F) -> Result<Syncer>
+    where
+        F: Fn() -> Result<()> + Send + 'static,
+    {
+        let (sender, receiver) = bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                let closed = !matches!(
+                    receiver.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                if let Err(e) = sync() {
+                    log::error!("background sync failed: {:?}", e);
+                }
+                if closed {
+                    break;
+                }
+            })?;
+        Ok(Syncer {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // closing the channel wakes the thread up for its last sync
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,32 +1,264 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::env::current_dir;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use super::durability::Durability;
+use super::lsm_engine::{LsmKvsEngine, LsmOptions};
+use super::memory_engine::MemoryKvsEngine;
 use super::sled_engine::SledKvsEngine;
-use super::store::KvStore;
+use super::snapshot::KvsSnapshot;
+use super::store::{KvStore, KvStoreOptions};
 
 use super::error::Result;
 
-pub trait KvsEngine: Send + 'static {
-    fn new() -> Result<Self>
-    where
-        Self: Sized;
-
-    fn open(path: impl Into<PathBuf>) -> Result<Self>
-    where
-        Self: Sized;
-    fn clone(&self) -> Self
-    where
-        Self: Sized;
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
-}
-
-// surprising that it will cause cyclic-dependencies
-pub fn get_engine_by_name(engine_name: &str) -> Box<dyn KvsEngine> {
-    let engine: Box<dyn KvsEngine> = match engine_name {
-        "kvs" => Box::new(KvStore::new().unwrap()),
-        "sled" => Box::new(SledKvsEngine::new().unwrap()),
-        _ => panic!("Unknown engine name"),
+/// Key-value pairs of a scan, in key order.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Key-value pairs of a byte scan, in the order of the key bytes.
+pub type BytesScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Sets and removes that `KvsEngine::write_batch` applies all together or not at all.
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set("key1", "value1");
+/// batch.remove(vec![0xff, 0x00]);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+    /// Removing a key that does not exist is not an error in a batch
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+        self
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.into()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+/// Operations of a key-value engine. Keys and values are bytes, the `String` methods are
+/// a convenience layer over the `_bytes` ones, reading a key or a value that is not valid
+/// utf-8 through them is an error.
+///
+/// The trait is object safe, so an engine chosen at runtime can be shared between threads
+/// as an `Arc<dyn KvsEngine>`. Constructors are in `KvsEngineFactory`.
+pub trait KvsEngine: Send + Sync + 'static {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()>;
+    /// Set the value of `key` for `ttl`, after which it reads as absent.
+    /// A later write of the key without a ttl makes it persistent again.
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()>;
+    /// Return the time left before `key` expires, or None if it never expires.
+    /// Return an error if the key does not exist.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>>;
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+    /// Apply every operation of the batch in order. After a crash either all of them or none are applied.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Replace the value of `key` with `new` only if it is `expected`, where None means absent.
+    /// Return false and change nothing if the current value is something else.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool>;
+    /// Scan the keys from `start` included to `end` excluded, or to the last key if `end` is None.
+    /// At most `limit` pairs are returned if it is given.
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter>;
+    /// Scan the keys starting with `prefix`.
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter> {
+        let pairs = self.scan_bytes(prefix, None, None)?;
+        let prefix = prefix.to_vec();
+        Ok(Box::new(pairs.take_while(move |pair| match pair {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+    /// Take a read-only view of the engine as it is now, see `KvsSnapshot`.
+    /// The writes keep going while the snapshot is read.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>>;
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+    fn set_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    fn remove(&self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<&str>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.map(str::as_bytes),
+            new.map(str::as_bytes),
+        )
+    }
+    /// Set `key` only if it does not exist yet. Return false if it exists.
+    fn set_if_absent(&self, key: &str, value: &str) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+    /// Remove `key` only if its value is `expected`. Return false if it is something else.
+    fn remove_if_equal(&self, key: &str, expected: &str) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+    fn scan(&self, start: &str, end: Option<&str>, limit: Option<usize>) -> Result<ScanIter> {
+        let pairs = self.scan_bytes(start.as_bytes(), end.map(str::as_bytes), limit)?;
+        Ok(Box::new(pairs.map(|pair| pair.and_then(utf8_pair))))
+    }
+    fn scan_prefix(&self, prefix: &str) -> Result<ScanIter> {
+        let pairs = self.scan_prefix_bytes(prefix.as_bytes())?;
+        Ok(Box::new(pairs.map(|pair| pair.and_then(utf8_pair))))
+    }
+}
+
+/// A shared engine, such as the `Arc<dyn KvsEngine>` of `get_engine_by_name`, is an engine too,
+/// so that it can be wrapped in a `CachedEngine`.
+impl<E: KvsEngine + ?Sized> KvsEngine for Arc<E> {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        (**self).set_bytes(key, value)
+    }
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        (**self).set_bytes_with_ttl(key, value, ttl)
+    }
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        (**self).ttl_bytes(key)
+    }
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get_bytes(key)
+    }
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        (**self).remove_bytes(key)
+    }
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        (**self).write_batch(batch)
+    }
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        (**self).compare_and_swap_bytes(key, expected, new)
+    }
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        (**self).scan_bytes(start, end, limit)
+    }
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter> {
+        (**self).scan_prefix_bytes(prefix)
+    }
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        (**self).snapshot()
+    }
+}
+
+/// Constructors of an engine, apart from `KvsEngine` so that it stays object safe.
+pub trait KvsEngineFactory: KvsEngine + Clone + Sized {
+    /// Open the engine in the current directory.
+    fn new() -> Result<Self> {
+        Self::open(current_dir()?)
+    }
+    /// Open the engine in `path`, creating it if needed.
+    fn open(path: impl Into<PathBuf>) -> Result<Self>;
+}
+
+fn utf8_pair((key, value): (Vec<u8>, Vec<u8>)) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+/// Open the engine called `engine_name` in `path`, to be shared between threads.
+/// The "memory" engine keeps nothing on disk and ignores `path`.
+pub fn get_engine_by_name(
+    engine_name: &str,
+    path: impl Into<PathBuf>,
+) -> Result<Arc<dyn KvsEngine>> {
+    let engine: Arc<dyn KvsEngine> = match engine_name {
+        "kvs" => Arc::new(KvStore::open(path)?),
+        "sled" => Arc::new(SledKvsEngine::open(path)?),
+        "memory" => Arc::new(MemoryKvsEngine::default()),
+        "lsm" => Arc::new(LsmKvsEngine::open(path)?),
+        _ => return Err(anyhow!("unknown engine: {}", engine_name).into()),
+    };
+    Ok(engine)
+}
+
+/// Open the engine called `engine_name` in `path` like `get_engine_by_name`, syncing its writes
+/// according to `durability` instead of the default of the engine. The "memory" engine has nothing to sync.
+pub fn get_engine_with_durability(
+    engine_name: &str,
+    path: impl Into<PathBuf>,
+    durability: Durability,
+) -> Result<Arc<dyn KvsEngine>> {
+    let engine: Arc<dyn KvsEngine> = match engine_name {
+        "kvs" => Arc::new(KvStore::open_with_options(
+            path,
+            KvStoreOptions::new().durability(durability),
+        )?),
+        "sled" => Arc::new(SledKvsEngine::open_with_durability(path, durability)?),
+        "memory" => Arc::new(MemoryKvsEngine::default()),
+        "lsm" => Arc::new(LsmKvsEngine::open_with_options(
+            path,
+            LsmOptions::new().durability(durability),
+        )?),
+        _ => return Err(anyhow!("unknown engine: {}", engine_name).into()),
     };
-    engine
+    Ok(engine)
 }
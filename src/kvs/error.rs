@@ -1,5 +1,7 @@
 use std::backtrace::Backtrace;
 use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
 use std::time;
 use thiserror::Error;
 
@@ -22,6 +24,20 @@ pub enum KvsError {
         source: serde_json::Error,
         backtrace: Backtrace,
     },
+    /// Encoding error of the client/server protocol
+    #[error("cbor serde error")]
+    Cbor {
+        #[from]
+        source: serde_cbor::Error,
+        backtrace: Backtrace,
+    },
+    /// A key or a value read through the string API is not valid utf-8
+    #[error("invalid utf-8")]
+    Utf8 {
+        #[from]
+        source: FromUtf8Error,
+        backtrace: Backtrace,
+    },
     /// System time Error type for KvStore
     #[error("system time error")]
     SystemTimeError {
@@ -38,6 +54,59 @@ pub enum KvsError {
     /// Key not found Error type for KvStore
     #[error("Key not found: {key})")]
     KeyNotFound { key: String, backtrace: Backtrace },
+    /// Corrupted record found in a log file
+    #[error("corrupted record in {file_id}.db at offset {offset}: {reason}")]
+    Corrupted {
+        file_id: u64,
+        offset: u64,
+        reason: String,
+        backtrace: Backtrace,
+    },
+    /// Log file written in an unknown format version
+    #[error("unsupported format version {version} in {file_id}.db")]
+    UnsupportedFormat {
+        file_id: u64,
+        version: u16,
+        backtrace: Backtrace,
+    },
+    /// Corrupted block, index or footer in a table file of the lsm engine
+    #[error("corrupted table {file_id}.sst: {reason}")]
+    CorruptedTable {
+        file_id: u64,
+        reason: String,
+        backtrace: Backtrace,
+    },
+    /// The data directory holds the data of another engine
+    #[error("cannot open {engine}: the directory holds {found} data")]
+    EngineMismatch {
+        engine: String,
+        found: String,
+        backtrace: Backtrace,
+    },
+    /// The data directory was written in a newer format than the engine reads
+    #[error("unsupported format version {version} of the {engine} engine")]
+    UnsupportedEngineFormat {
+        engine: String,
+        version: u16,
+        backtrace: Backtrace,
+    },
+    /// Another process has the data directory open
+    #[error("{dir:?} is locked by another process")]
+    Locked { dir: PathBuf, backtrace: Backtrace },
+    /// A write to a store opened read-only
+    #[error("the store is opened read-only")]
+    ReadOnly { backtrace: Backtrace },
+    /// A migration between engines could not run or did not copy every key
+    #[error("migration from {from} to {to}: {reason}")]
+    Migration {
+        from: String,
+        to: String,
+        reason: String,
+        backtrace: Backtrace,
+    },
+    /// A durability policy that cannot be parsed
+    #[error("invalid durability {value:?}, expected always, every:<writes>, interval:<ms> or os")]
+    InvalidDurability { value: String, backtrace: Backtrace },
     /// Unexpected command
     #[error("unexpected command: {command})")]
     UnexpectedCommand {
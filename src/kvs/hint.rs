@@ -0,0 +1,133 @@
+// Hint files let KvStore::open load the index of an immutable log file without reading its records.
+//
+// N.hint is written by compaction next to the compacted N.db:
+//
+// hint:  | magic "KVSHINT\0" (8) | format version u16 | db_len u64 | entry | entry | ... | crc32 u32 |
+// entry: | key_len u32 | file_id u64 | offset u64 | size u64 | expire_at u64 | removed u8 | key |
+//
+// A removed entry is a tombstone kept by compaction, it hides the key in older log files.
+// expire_at is copied from the record so that an expired key is known without reading it.
+//
+// db_len is the size of N.db when the hint was written, a hint that does not match its log file,
+// or that fails the crc, is ignored and the log file is scanned instead.
+use super::error::Result;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+const HINT_MAGIC: &[u8; 8] = b"KVSHINT\0";
+const HINT_VERSION: u16 = 3;
+const HINT_HEADER_SIZE: usize = 18;
+const HINT_ENTRY_HEADER_SIZE: usize = 37;
+
+/// Location of the live record or the tombstone of a key, as stored in a hint file.
+pub(crate) struct HintEntry {
+    pub(crate) key: Vec<u8>,
+    pub(crate) file_id: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) expire_at: u64,
+    pub(crate) removed: bool,
+}
+
+pub(crate) fn hint_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.hint", file_id))
+}
+
+/// Write the hint of log file `file_id`, which is `db_len` bytes long.
+/// The hint is written to a temporary file first and renamed, so it is either complete or absent.
+pub(crate) fn write_hint_file(
+    dir: &Path,
+    file_id: u64,
+    db_len: u64,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let mut buf = Vec::with_capacity(HINT_HEADER_SIZE + entries.len() * HINT_ENTRY_HEADER_SIZE);
+    buf.extend_from_slice(HINT_MAGIC);
+    buf.extend_from_slice(&HINT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&db_len.to_le_bytes());
+    for entry in entries {
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry.file_id.to_le_bytes());
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&entry.expire_at.to_le_bytes());
+        buf.push(entry.removed as u8);
+        buf.extend_from_slice(&entry.key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let path = hint_path(dir, file_id);
+    let tmp_path = path.with_extension("hint.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&buf)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Load the hint of log file `file_id`.
+/// Return None if there is no hint, or if it is not valid for a log file of `db_len` bytes.
+pub(crate) fn read_hint_file(
+    dir: &Path,
+    file_id: u64,
+    db_len: u64,
+) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(hint_path(dir, file_id)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match parse_hint(&buf, db_len) {
+        Some(entries) => Ok(Some(entries)),
+        None => {
+            log::warn!("ignore invalid hint file {}.hint", file_id);
+            Ok(None)
+        }
+    }
+}
+
+fn parse_hint(buf: &[u8], db_len: u64) -> Option<Vec<HintEntry>> {
+    if buf.len() < HINT_HEADER_SIZE + 4 {
+        return None;
+    }
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(content) != u32::from_le_bytes(crc.try_into().ok()?) {
+        return None;
+    }
+    let u64_at = |pos: usize| u64::from_le_bytes(content[pos..pos + 8].try_into().unwrap());
+    if &content[..8] != HINT_MAGIC
+        || u16::from_le_bytes([content[8], content[9]]) != HINT_VERSION
+        || u64_at(10) != db_len
+    {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut pos = HINT_HEADER_SIZE;
+    while pos < content.len() {
+        if content.len() - pos < HINT_ENTRY_HEADER_SIZE {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(content[pos..pos + 4].try_into().unwrap()) as usize;
+        let key_start = pos + HINT_ENTRY_HEADER_SIZE;
+        let key = content.get(key_start..key_start + key_len)?;
+        entries.push(HintEntry {
+            key: key.to_vec(),
+            file_id: u64_at(pos + 4),
+            offset: u64_at(pos + 12),
+            size: u64_at(pos + 20),
+            expire_at: u64_at(pos + 28),
+            removed: match content[pos + 36] {
+                0 => false,
+                1 => true,
+                _ => return None,
+            },
+        });
+        pos = key_start + key_len;
+    }
+    Some(entries)
+}
@@ -0,0 +1,999 @@
+// A log-structured merge tree engine, for datasets that do not fit in memory.
+//
+// Writes are appended to a write-ahead log N.wal, in the record format of the KvStore log files,
+// and inserted in the memtable, a sorted map in memory. Once the memtable is large enough it is written
+// to a table file N.sst of level 0 and a new log is started, see sstable.rs for the table format.
+//
+// The tables of level 0 may overlap and are searched newest first, the tables of a deeper level each cover
+// a distinct key range. Compaction is leveled: once level 0 has `level0_tables` tables they are merged with
+// the overlapping tables of level 1, and once a deeper level is over its size budget one of its tables is
+// merged into the next level. Tombstones and expired values are only dropped when merged into the deepest level.
+//
+// lsm.manifest lists the tables of every level, it is replaced atomically after every flush and compaction.
+// Tables it does not list are leftovers of an interrupted flush or compaction, they are removed on open.
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::durability::{Durability, Syncer};
+use super::engine::{BatchOp, BytesScanIter, KvsEngine, KvsEngineFactory, WriteBatch};
+use super::error::{KvsError, Result};
+use super::meta::{open_meta, MetaGuard};
+use super::record::{
+    read_segment_format, write_segment_header, Command, Record, RecordReader, SegmentFormat,
+    SEGMENT_HEADER_SIZE,
+};
+use super::snapshot::KvsSnapshot;
+use super::sstable::{Entry, Table, TableBuilder};
+
+const MANIFEST_FILE: &str = "lsm.manifest";
+
+/// Tuning of an `LsmKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    memtable_size: u64,
+    table_size: u64,
+    level0_tables: usize,
+    level1_size: u64,
+    level_size_ratio: u64,
+    durability: Durability,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level1_size: 10 * 1024 * 1024,
+            level_size_ratio: 10,
+            durability: Durability::OsBuffered,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the memtable to a table once it holds `size` bytes.
+    pub fn memtable_size(mut self, size: u64) -> Self {
+        self.memtable_size = size;
+        self
+    }
+
+    /// Split the output of a compaction in tables of about `size` bytes.
+    pub fn table_size(mut self, size: u64) -> Self {
+        self.table_size = size;
+        self
+    }
+
+    /// Merge level 0 into level 1 once it has `count` tables.
+    pub fn level0_tables(mut self, count: usize) -> Self {
+        self.level0_tables = count.max(1);
+        self
+    }
+
+    /// Size budget of level 1, each deeper level gets `ratio` times the budget of the level above.
+    pub fn level_size(mut self, level1_size: u64, ratio: u64) -> Self {
+        self.level1_size = level1_size;
+        self.level_size_ratio = ratio.max(2);
+        self
+    }
+
+    /// Sync the write-ahead log according to `durability`, see `Durability`. Defaults to `OsBuffered`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        (1..level).fold(self.level1_size, |size, _| {
+            size.saturating_mul(self.level_size_ratio)
+        })
+    }
+}
+
+type MemTable = BTreeMap<Vec<u8>, Entry>;
+
+type EntryIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>> + Send>;
+
+/// A `KvsEngine` storing its keys in a log-structured merge tree, only the memtable,
+/// and the index and bloom filter of every table, are kept in memory.
+/// ```rust
+/// # use kvs::{KvsEngine, KvsEngineFactory, LsmKvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let engine = LsmKvsEngine::open(dir.path())?;
+/// engine.set("key1", "value1")?;
+/// assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    inner: Arc<LsmInner>,
+    // only for `Durability::Interval`
+    _syncer: Option<Arc<Syncer>>,
+}
+
+struct LsmInner {
+    dir: PathBuf,
+    options: LsmOptions,
+    memtables: RwLock<MemTables>,
+    version: RwLock<Arc<Version>>,
+    // taken by every write, flush and compaction, so they run one at a time
+    writer: Mutex<Writer>,
+    // marks the directory as cleanly shut down once dropped
+    _meta: MetaGuard,
+}
+
+#[derive(Default)]
+struct MemTables {
+    active: MemTable,
+    active_size: u64,
+    // memtable being written to a table, still searched until the table is installed
+    frozen: Option<Arc<MemTable>>,
+}
+
+impl MemTables {
+    fn apply(&mut self, record: Record) {
+        let entry = match record.command {
+            Command::Set => Entry {
+                value: Some(record.value),
+                expire_at: record.expire_at,
+            },
+            Command::Remove => Entry {
+                value: None,
+                expire_at: 0,
+            },
+            _ => return,
+        };
+        self.active_size += entry.encoded_len(&record.key) as u64;
+        self.active.insert(record.key, entry);
+    }
+}
+
+/// The tables of every level, replaced as a whole by flushes and compactions.
+#[derive(Clone, Default)]
+struct Version {
+    // level 0 is newest first, the deeper levels are sorted by key
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        for (level, tables) in self.levels.iter().enumerate() {
+            if level == 0 {
+                for table in tables.iter().filter(|table| table.overlaps(key, key)) {
+                    if let Some(entry) = table.get(key)? {
+                        return Ok(Some(entry));
+                    }
+                }
+                continue;
+            }
+            let pos = tables.partition_point(|table| table.last_key() < key);
+            if let Some(table) = tables.get(pos).filter(|table| table.first_key() <= key) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn level_size(&self, level: usize) -> u64 {
+        self.levels[level].iter().map(|table| table.size()).sum()
+    }
+
+    fn without(&self, removed: &[Arc<Table>]) -> Version {
+        let removed: HashSet<u64> = removed.iter().map(|table| table.file_id).collect();
+        Version {
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| {
+                    tables
+                        .iter()
+                        .filter(|table| !removed.contains(&table.file_id))
+                        .cloned()
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+struct Writer {
+    wal: File,
+    wal_id: u64,
+    // end of the last complete write to the log, a failed one is cut back to it
+    wal_len: u64,
+    // set while the log holds the tail of a failed write, it is cut off before anything else is appended
+    wal_torn: bool,
+    // logs of the frozen memtable, removed once it is written to a table
+    old_wal_ids: Vec<u64>,
+    next_file_id: u64,
+    durability: Durability,
+    // appends to the log since it was last synced
+    unsynced_writes: u64,
+}
+
+impl Writer {
+    fn sync_wal(&mut self) -> Result<()> {
+        if self.unsynced_writes > 0 {
+            self.wal.sync_data()?;
+            self.unsynced_writes = 0;
+        }
+        Ok(())
+    }
+
+    /// Cut the log back to the end of the last complete write, if a failed write left something after it.
+    fn reset_wal(&mut self) -> Result<()> {
+        if self.wal_torn {
+            self.wal.set_len(self.wal_len)?;
+            self.wal_torn = false;
+        }
+        Ok(())
+    }
+
+    fn next_file_id(&mut self) -> u64 {
+        let file_id = self.next_file_id;
+        self.next_file_id += 1;
+        file_id
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    next_file_id: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl KvsEngineFactory for LsmKvsEngine {
+    fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, LsmOptions::default())
+    }
+}
+
+impl LsmKvsEngine {
+    /// Open the engine in `path` with the given options, replaying the logs into the memtable.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmOptions,
+    ) -> Result<LsmKvsEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let meta = open_meta(&dir, "lsm")?;
+        let manifest = read_manifest(&dir)?;
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|file_ids| {
+                file_ids
+                    .iter()
+                    .map(|file_id| Table::open(&dir, *file_id).map(Arc::new))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let live_tables: HashSet<u64> = manifest.levels.iter().flatten().cloned().collect();
+
+        let mut next_file_id = manifest.next_file_id;
+        let mut wal_ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let file_id = match path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok())
+            {
+                Some(file_id) => file_id,
+                None => continue,
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("wal") => wal_ids.push(file_id),
+                Some("sst") if !live_tables.contains(&file_id) => fs::remove_file(&path)?,
+                Some("sst") => {}
+                _ => continue,
+            }
+            next_file_id = next_file_id.max(file_id + 1);
+        }
+        wal_ids.sort_unstable();
+
+        let mut memtables = MemTables::default();
+        for (i, wal_id) in wal_ids.iter().enumerate() {
+            replay_wal(&dir, *wal_id, i + 1 == wal_ids.len(), &mut memtables)?;
+        }
+        let wal_id = next_file_id;
+        let writer = Writer {
+            wal: create_wal(&dir, wal_id)?,
+            wal_id,
+            wal_len: SEGMENT_HEADER_SIZE,
+            wal_torn: false,
+            old_wal_ids: wal_ids,
+            next_file_id: wal_id + 1,
+            durability: options.durability,
+            unsynced_writes: 0,
+        };
+        let interval = options.durability.interval();
+        let inner = Arc::new(LsmInner {
+            dir,
+            options,
+            memtables: RwLock::new(memtables),
+            version: RwLock::new(Arc::new(Version { levels })),
+            writer: Mutex::new(writer),
+            _meta: meta,
+        });
+        let syncer = match interval {
+            Some(interval) => {
+                let inner = inner.clone();
+                let syncer =
+                    Syncer::spawn("lsm-sync", interval, move || sync_in_background(&inner))?;
+                Some(Arc::new(syncer))
+            }
+            None => None,
+        };
+        Ok(LsmKvsEngine {
+            inner,
+            _syncer: syncer,
+        })
+    }
+
+    /// Write the memtable to a table and merge every table into the deepest level,
+    /// dropping the tombstones and the expired values.
+    pub fn compact(&self) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.flush(&mut writer)?;
+        let version = self.version();
+        // level 0 newest first, then each deeper level, is from the newest entries to the oldest
+        let inputs: Vec<Arc<Table>> = version.levels.iter().flatten().cloned().collect();
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        let output_level = version.levels.len().max(2) - 1;
+        let outputs = self.write_tables(&mut writer, merge_tables(&inputs)?, true)?;
+        let mut levels = vec![Vec::new(); output_level + 1];
+        levels[output_level] = outputs;
+        self.install(&writer, Version { levels })?;
+        self.remove_tables(&inputs)
+    }
+
+    fn version(&self) -> Arc<Version> {
+        self.inner.version.read().unwrap().clone()
+    }
+
+    /// Return the latest entry of `key`, which may be a tombstone or an expired value.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Entry>> {
+        {
+            let memtables = self.inner.memtables.read().unwrap();
+            if let Some(entry) = memtables.active.get(key) {
+                return Ok(Some(entry.clone()));
+            }
+            if let Some(entry) = memtables.frozen.as_ref().and_then(|frozen| frozen.get(key)) {
+                return Ok(Some(entry.clone()));
+            }
+        }
+        // a flush installs its table before it drops the frozen memtable, so the key cannot be missed
+        self.version().get(key)
+    }
+
+    fn live_value(&self, key: &[u8], now: u64) -> Result<Option<Entry>> {
+        Ok(self.lookup(key)?.filter(|entry| entry.is_live(now)))
+    }
+
+    /// Append the records to the log with a single write, then apply them all at once to the memtable.
+    fn write(&self, writer: &mut Writer, records: Vec<Record>) -> Result<()> {
+        let mut buf = Vec::new();
+        for record in &records {
+            buf.extend_from_slice(&record.encode());
+        }
+        // replay stops at the first bad record, so nothing is appended after the tail of a failed write
+        writer.reset_wal()?;
+        writer.wal_torn = true;
+        writer.unsynced_writes += 1;
+        let written = writer
+            .wal
+            .write_all(&buf)
+            .map_err(KvsError::from)
+            .and_then(|_| {
+                if writer.durability.needs_sync(writer.unsynced_writes) {
+                    writer.sync_wal()?;
+                }
+                Ok(())
+            });
+        if let Err(e) = written {
+            // nothing of a failed write is applied, it must not be replayed either
+            writer.reset_wal()?;
+            return Err(e);
+        }
+        writer.wal_len += buf.len() as u64;
+        writer.wal_torn = false;
+        let full = {
+            let mut memtables = self.inner.memtables.write().unwrap();
+            for record in records {
+                memtables.apply(record);
+            }
+            memtables.active_size >= self.inner.options.memtable_size
+        };
+        if full {
+            self.flush(writer)?;
+            self.maybe_compact(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Write the memtable to a new table of level 0, and start a new log.
+    fn flush(&self, writer: &mut Writer) -> Result<()> {
+        let frozen = {
+            let mut memtables = self.inner.memtables.write().unwrap();
+            match &memtables.frozen {
+                // left by a flush that failed, it is written first
+                Some(frozen) => frozen.clone(),
+                None if memtables.active.is_empty() => return Ok(()),
+                None => {
+                    let frozen = Arc::new(mem::take(&mut memtables.active));
+                    memtables.active_size = 0;
+                    memtables.frozen = Some(frozen.clone());
+                    // the background sync only sees the current log
+                    if writer.durability != Durability::OsBuffered {
+                        writer.sync_wal()?;
+                    }
+                    writer.unsynced_writes = 0;
+                    writer.reset_wal()?;
+                    let wal_id = writer.next_file_id();
+                    let wal = create_wal(&self.inner.dir, wal_id)?;
+                    writer
+                        .old_wal_ids
+                        .push(mem::replace(&mut writer.wal_id, wal_id));
+                    writer.wal = wal;
+                    writer.wal_len = SEGMENT_HEADER_SIZE;
+                    frozen
+                }
+            }
+        };
+        let mut builder = TableBuilder::new(&self.inner.dir, writer.next_file_id())?;
+        for (key, entry) in frozen.iter() {
+            builder.add(key, entry)?;
+        }
+        let table = Arc::new(builder.finish()?);
+        let mut version = (*self.version()).clone();
+        if version.levels.is_empty() {
+            version.levels.push(Vec::new());
+        }
+        version.levels[0].insert(0, table);
+        self.install(writer, version)?;
+        self.inner.memtables.write().unwrap().frozen = None;
+        for wal_id in writer.old_wal_ids.drain(..) {
+            fs::remove_file(wal_path(&self.inner.dir, wal_id))?;
+        }
+        Ok(())
+    }
+
+    fn maybe_compact(&self, writer: &mut Writer) -> Result<()> {
+        loop {
+            let version = self.version();
+            let options = &self.inner.options;
+            let level = if version.levels[0].len() >= options.level0_tables {
+                0
+            } else {
+                match (1..version.levels.len())
+                    .find(|level| version.level_size(*level) > options.max_level_size(*level))
+                {
+                    Some(level) => level,
+                    None => return Ok(()),
+                }
+            };
+            self.compact_level(writer, &version, level)?;
+        }
+    }
+
+    /// Merge level 0, or the first table of a deeper level, with the overlapping tables of the next level.
+    fn compact_level(&self, writer: &mut Writer, version: &Version, level: usize) -> Result<()> {
+        let inputs = if level == 0 {
+            version.levels[0].clone()
+        } else {
+            vec![version.levels[level][0].clone()]
+        };
+        let start = inputs.iter().map(|table| table.first_key()).min().unwrap();
+        let end = inputs.iter().map(|table| table.last_key()).max().unwrap();
+        let output_level = level + 1;
+        let overlapping: Vec<Arc<Table>> = version
+            .levels
+            .get(output_level)
+            .map(|tables| {
+                tables
+                    .iter()
+                    .filter(|table| table.overlaps(start, end))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let bottom = version
+            .levels
+            .iter()
+            .skip(output_level + 1)
+            .all(Vec::is_empty);
+
+        let merged: Vec<Arc<Table>> = inputs.iter().chain(&overlapping).cloned().collect();
+        let outputs = self.write_tables(writer, merge_tables(&merged)?, bottom)?;
+        let mut version = version.without(&merged);
+        if version.levels.len() <= output_level {
+            version.levels.push(Vec::new());
+        }
+        version.levels[output_level].extend(outputs);
+        version.levels[output_level].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.install(writer, version)?;
+        self.remove_tables(&merged)
+    }
+
+    /// Write the merged entries to tables of about `table_size` bytes.
+    fn write_tables(
+        &self,
+        writer: &mut Writer,
+        entries: MergeIter,
+        drop_dead: bool,
+    ) -> Result<Vec<Arc<Table>>> {
+        let now = now_micros()?;
+        let mut tables = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for pair in entries {
+            let (key, entry) = pair?;
+            if drop_dead && !entry.is_live(now) {
+                continue;
+            }
+            let table = match &mut builder {
+                Some(builder) => builder,
+                None => builder.insert(TableBuilder::new(&self.inner.dir, writer.next_file_id())?),
+            };
+            table.add(&key, &entry)?;
+            if table.size() >= self.inner.options.table_size {
+                tables.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+        if let Some(builder) = builder {
+            tables.push(Arc::new(builder.finish()?));
+        }
+        Ok(tables)
+    }
+
+    /// Write the manifest of `version`, then make it the current one.
+    fn install(&self, writer: &Writer, version: Version) -> Result<()> {
+        let manifest = Manifest {
+            next_file_id: writer.next_file_id,
+            levels: version
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.file_id).collect())
+                .collect(),
+        };
+        write_manifest(&self.inner.dir, &manifest)?;
+        *self.inner.version.write().unwrap() = Arc::new(version);
+        Ok(())
+    }
+
+    // the files are removed once the readers, scans and snapshots still holding the previous version drop it
+    fn remove_tables(&self, tables: &[Arc<Table>]) -> Result<()> {
+        for table in tables {
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let record = set_record(key, value, 0)?;
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.write(&mut writer, vec![record])
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expire_at = now_micros()?.saturating_add(ttl.as_micros() as u64);
+        let record = set_record(key, value, expire_at)?;
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.write(&mut writer, vec![record])
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let now = now_micros()?;
+        match self.live_value(key, now)? {
+            Some(entry) if entry.expire_at == 0 => Ok(None),
+            Some(entry) => Ok(Some(Duration::from_micros(entry.expire_at - now))),
+            None => Err(key_not_found(key)),
+        }
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .live_value(key, now_micros()?)?
+            .and_then(|entry| entry.value))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        if self.live_value(key, now_micros()?)?.is_none() {
+            return Err(key_not_found(key));
+        }
+        let record = remove_record(key)?;
+        self.write(&mut writer, vec![record])
+    }
+
+    /// The batch is logged between a begin and a commit record, and applied to the memtable at once.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut records = vec![marker(Command::BatchBegin)?];
+        for op in batch.into_ops() {
+            records.push(match op {
+                BatchOp::Set(key, value) => set_record(&key, &value, 0)?,
+                BatchOp::Remove(key) => remove_record(&key)?,
+            });
+        }
+        records.push(marker(Command::BatchCommit)?);
+        let mut writer = self.inner.writer.lock().unwrap();
+        self.write(&mut writer, records)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut writer = self.inner.writer.lock().unwrap();
+        let current = self
+            .live_value(key, now_micros()?)?
+            .and_then(|entry| entry.value);
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        let record = match new {
+            Some(value) => set_record(key, value, 0)?,
+            None if current.is_some() => remove_record(key)?,
+            // absent and expected to stay absent
+            None => return Ok(true),
+        };
+        self.write(&mut writer, vec![record])?;
+        Ok(true)
+    }
+
+    /// The memtables are copied when the scan starts, the tables are read lazily.
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let memtables = {
+            let memtables = self.inner.memtables.read().unwrap();
+            let mut sources = vec![memtable_range(&memtables.active, start, end)];
+            if let Some(frozen) = &memtables.frozen {
+                sources.push(memtable_range(frozen, start, end));
+            }
+            sources
+        };
+        merge_scan(memtables, &self.version(), start, end, limit, now_micros()?)
+    }
+
+    /// The active memtable is copied, the frozen one and the tables are shared with the engine.
+    /// A table merged away by compaction is only removed once the snapshot is dropped.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let now = now_micros()?;
+        let memtables = self.inner.memtables.read().unwrap();
+        // a flush installs its table before it drops the frozen memtable,
+        // so the version read here holds everything the memtables no longer do
+        let version = self.version();
+        Ok(Box::new(LsmSnapshot {
+            active: memtables.active.clone(),
+            frozen: memtables.frozen.clone(),
+            version,
+            now,
+        }))
+    }
+}
+
+/// Memtables and tables of a `LsmKvsEngine` at the time the snapshot was taken.
+struct LsmSnapshot {
+    active: MemTable,
+    frozen: Option<Arc<MemTable>>,
+    version: Arc<Version>,
+    now: u64,
+}
+
+impl KvsSnapshot for LsmSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entry = match self
+            .active
+            .get(key)
+            .or_else(|| self.frozen.as_ref().and_then(|frozen| frozen.get(key)))
+        {
+            Some(entry) => Some(entry.clone()),
+            None => self.version.get(key)?,
+        };
+        Ok(entry
+            .filter(|entry| entry.is_live(self.now))
+            .and_then(|entry| entry.value))
+    }
+
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let mut memtables = vec![memtable_range(&self.active, start, end)];
+        if let Some(frozen) = &self.frozen {
+            memtables.push(memtable_range(frozen, start, end));
+        }
+        merge_scan(memtables, &self.version, start, end, limit, self.now)
+    }
+}
+
+/// The entries of `memtable` from `start` to `end`, copied out so that no lock is held.
+fn memtable_range(memtable: &MemTable, start: &[u8], end: Option<&[u8]>) -> EntryIter {
+    let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+    let pairs: Vec<_> = memtable
+        .range::<[u8], _>((Bound::Included(start), upper))
+        .map(|(key, entry)| Ok((key.clone(), entry.clone())))
+        .collect();
+    Box::new(pairs.into_iter())
+}
+
+/// Merge the memtable entries, newest first, with the tables of `version` into the live pairs.
+fn merge_scan(
+    mut sources: Vec<EntryIter>,
+    version: &Version,
+    start: &[u8],
+    end: Option<&[u8]>,
+    limit: Option<usize>,
+    now: u64,
+) -> Result<BytesScanIter> {
+    for (level, tables) in version.levels.iter().enumerate() {
+        let tables: Vec<Arc<Table>> = tables
+            .iter()
+            .filter(|table| table.last_key() >= start)
+            .cloned()
+            .collect();
+        if level == 0 {
+            for table in tables {
+                sources.push(Box::new(Table::iter_from(table, start)));
+            }
+        } else {
+            let start = start.to_vec();
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| Table::iter_from(table, &start)),
+            ));
+        }
+    }
+
+    let end = end.map(<[u8]>::to_vec);
+    let pairs = MergeIter::new(sources)?
+        .take_while(move |pair| match (pair, &end) {
+            (Ok((key, _)), Some(end)) => key < end,
+            _ => true,
+        })
+        .filter_map(move |pair| match pair {
+            Ok((key, entry)) if entry.is_live(now) => Some(Ok((key, entry.value?))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .take(limit.unwrap_or(usize::MAX));
+    Ok(Box::new(pairs))
+}
+
+/// Merge sources sorted by key, when several have the same key the first one wins.
+struct MergeIter {
+    sources: Vec<EntryIter>,
+    heads: Vec<Option<(Vec<u8>, Entry)>>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<EntryIter>) -> Result<MergeIter> {
+        let mut merge = MergeIter {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+        };
+        for source_id in 0..merge.sources.len() {
+            merge.advance(source_id)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, source_id: usize) -> Result<()> {
+        self.heads[source_id] = self.sources[source_id].next().transpose()?;
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut min: Option<(usize, &[u8])> = None;
+        for (source_id, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                if min.is_none_or(|(_, min_key)| key.as_slice() < min_key) {
+                    min = Some((source_id, key));
+                }
+            }
+        }
+        let source_id = min?.0;
+        let (key, entry) = self.heads[source_id].take().unwrap();
+        let mut result = self.advance(source_id);
+        // the older entries of the key are shadowed
+        for other_id in source_id + 1..self.heads.len() {
+            if matches!(&self.heads[other_id], Some((other_key, _)) if *other_key == key) {
+                result = result.and_then(|()| self.advance(other_id));
+            }
+        }
+        Some(result.map(|()| (key, entry)))
+    }
+}
+
+// tables from the newest to the oldest
+fn merge_tables(tables: &[Arc<Table>]) -> Result<MergeIter> {
+    MergeIter::new(
+        tables
+            .iter()
+            .map(|table| Box::new(Table::iter_from(table.clone(), &[])) as EntryIter)
+            .collect(),
+    )
+}
+
+fn wal_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", file_id))
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // nothing acknowledged is left unsynced by a clean shutdown, unless the OS is trusted with it
+        if self.durability != Durability::OsBuffered {
+            if let Err(e) = self.sync_wal() {
+                log::error!("syncing {}.wal failed: {:?}", self.wal_id, e);
+            }
+        }
+    }
+}
+
+// sync for `Durability::Interval`, writers go on while the log is synced
+fn sync_in_background(inner: &LsmInner) -> Result<()> {
+    let wal = {
+        let mut writer = inner.writer.lock().unwrap();
+        if writer.unsynced_writes == 0 {
+            return Ok(());
+        }
+        writer.unsynced_writes = 0;
+        writer.wal.try_clone()?
+    };
+    wal.sync_data()?;
+    Ok(())
+}
+
+fn create_wal(dir: &Path, file_id: u64) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(wal_path(dir, file_id))?;
+    write_segment_header(&mut file)?;
+    Ok(file)
+}
+
+/// Apply the records of a log to the memtable. A write batch is only applied once its commit is read.
+/// The tail of the last log may be torn by a crash, it is cut off.
+fn replay_wal(dir: &Path, file_id: u64, last: bool, memtables: &mut MemTables) -> Result<()> {
+    let path = wal_path(dir, file_id);
+    let file = File::open(&path)?;
+    match read_segment_format(&file, file_id)? {
+        SegmentFormat::Binary => {}
+        SegmentFormat::Empty => return Ok(()),
+        _ => {
+            return Err(KvsError::Corrupted {
+                file_id,
+                offset: 0,
+                reason: "unexpected format of a write-ahead log".to_string(),
+                backtrace: Backtrace::force_capture(),
+            })
+        }
+    }
+    let mut reader = RecordReader::new(BufReader::new(&file), file_id, file.metadata()?.len());
+    let mut batch: Option<Vec<Record>> = None;
+    loop {
+        match reader.next_record() {
+            Ok(Some((_, _, record))) => match (record.command.clone(), &mut batch) {
+                (Command::BatchBegin, _) => batch = Some(Vec::new()),
+                (Command::BatchCommit, _) => {
+                    for record in batch.take().unwrap_or_default() {
+                        memtables.apply(record);
+                    }
+                }
+                (_, Some(batch)) => batch.push(record),
+                (_, None) => memtables.apply(record),
+            },
+            Ok(None) => return Ok(()),
+            Err(KvsError::Corrupted { .. }) if last => {
+                let pos = reader.pos();
+                OpenOptions::new().write(true).open(&path)?.set_len(pos)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    match File::open(dir.join(MANIFEST_FILE)) {
+        Ok(file) => Ok(serde_cbor::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// written to a temporary file and renamed, so that it is either the old or the new one after a crash
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let tmp_path = path.with_extension("manifest.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_cbor::to_vec(manifest)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+fn set_record(key: &[u8], value: &[u8], expire_at: u64) -> Result<Record> {
+    Ok(Record {
+        command: Command::Set,
+        tstamp: now_micros()?,
+        expire_at,
+        key: key.to_vec(),
+        value: value.to_vec(),
+    })
+}
+
+fn remove_record(key: &[u8]) -> Result<Record> {
+    Ok(Record {
+        command: Command::Remove,
+        tstamp: now_micros()?,
+        expire_at: 0,
+        key: key.to_vec(),
+        value: Vec::new(),
+    })
+}
+
+fn marker(command: Command) -> Result<Record> {
+    Ok(Record {
+        command,
+        tstamp: now_micros()?,
+        expire_at: 0,
+        key: Vec::new(),
+        value: Vec::new(),
+    })
+}
+
+fn key_not_found(key: &[u8]) -> KvsError {
+    KvsError::KeyNotFound {
+        key: String::from_utf8_lossy(key).to_string(),
+        backtrace: Backtrace::force_capture(),
+    }
+}
+
+fn now_micros() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
@@ -0,0 +1,264 @@
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::KvsError;
+
+use super::engine::{BatchOp, BytesScanIter, KvsEngine, KvsEngineFactory, WriteBatch};
+use super::error::Result;
+use super::meta::{open_meta, MetaGuard};
+use super::snapshot::{CopiedSnapshot, KvsSnapshot};
+
+const SNAPSHOT_FILE: &str = "memory.snapshot";
+
+/// A `KvsEngine` keeping every key in memory, with no disk I/O, for tests and ephemeral caches.
+///
+/// `MemoryKvsEngine::default()` starts empty and loses everything once dropped.
+/// Opened with `KvsEngineFactory::open`, it loads the snapshot left in the directory if any,
+/// and writes a new one when the last handle is dropped.
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryKvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// let engine = MemoryKvsEngine::default();
+/// engine.set("key1", "value1")?;
+/// assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    map: RwLock<BTreeMap<Vec<u8>, Entry>>,
+    snapshot: Option<PathBuf>,
+    // dropped after the snapshot is written
+    _meta: Option<MetaGuard>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "serde_bytes")]
+    value: Vec<u8>,
+    // in micros since the epoch, None if the key never expires
+    expire_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_live(&self, now: u64) -> bool {
+        !matches!(self.expire_at, Some(expire_at) if expire_at <= now)
+    }
+}
+
+impl KvsEngineFactory for MemoryKvsEngine {
+    /// Load the snapshot in `path` if there is one, the keys are written back there on drop.
+    fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let meta = open_meta(&dir, "memory")?;
+        let snapshot = dir.join(SNAPSHOT_FILE);
+        let map = match File::open(&snapshot) {
+            Ok(file) => {
+                let entries: BTreeMap<ByteBuf, Entry> =
+                    serde_cbor::from_reader(BufReader::new(file))?;
+                entries
+                    .into_iter()
+                    .map(|(key, entry)| (key.into_vec(), entry))
+                    .collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(MemoryKvsEngine {
+            inner: Arc::new(Inner {
+                map: RwLock::new(map),
+                snapshot: Some(snapshot),
+                _meta: Some(meta),
+            }),
+        })
+    }
+}
+
+impl MemoryKvsEngine {
+    fn insert(&self, key: &[u8], value: &[u8], expire_at: Option<u64>) -> Result<()> {
+        let entry = Entry {
+            value: value.to_vec(),
+            expire_at,
+        };
+        self.inner.map.write().unwrap().insert(key.to_vec(), entry);
+        Ok(())
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expire_at = now_micros()?.saturating_add(ttl.as_micros() as u64);
+        self.insert(key, value, Some(expire_at))
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let now = now_micros()?;
+        match self.inner.map.read().unwrap().get(key) {
+            Some(entry) if entry.is_live(now) => Ok(entry
+                .expire_at
+                .map(|expire_at| Duration::from_micros(expire_at - now))),
+            _ => Err(key_not_found(key)),
+        }
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_micros()?;
+        let map = self.inner.map.read().unwrap();
+        Ok(map
+            .get(key)
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| entry.value.clone()))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let now = now_micros()?;
+        match self.inner.map.write().unwrap().remove(key) {
+            Some(entry) if entry.is_live(now) => Ok(()),
+            _ => Err(key_not_found(key)),
+        }
+    }
+
+    /// The whole batch is applied under the write lock, readers see all of it or none.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut map = self.inner.map.write().unwrap();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set(key, value) => {
+                    map.insert(
+                        key,
+                        Entry {
+                            value,
+                            expire_at: None,
+                        },
+                    );
+                }
+                BatchOp::Remove(key) => {
+                    map.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let now = now_micros()?;
+        let mut map = self.inner.map.write().unwrap();
+        let current = map
+            .get(key)
+            .filter(|entry| entry.is_live(now))
+            .map(|entry| entry.value.as_slice());
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => map.insert(
+                key.to_vec(),
+                Entry {
+                    value: value.to_vec(),
+                    expire_at: None,
+                },
+            ),
+            None => map.remove(key),
+        };
+        Ok(true)
+    }
+
+    /// The pairs are copied out of the map when the scan starts, later writes are not seen.
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let now = now_micros()?;
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let map = self.inner.map.read().unwrap();
+        let pairs: Vec<_> = map
+            .range::<[u8], _>((Bound::Included(start), end))
+            .filter(|(_, entry)| entry.is_live(now))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, entry)| Ok((key.clone(), entry.value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    /// The live pairs are copied under the read lock, which holds the writes back meanwhile.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let now = now_micros()?;
+        let map = self.inner.map.read().unwrap();
+        let pairs = map
+            .iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect();
+        Ok(Box::new(CopiedSnapshot::new(pairs)))
+    }
+}
+
+impl Inner {
+    // write to a temporary file first, so that a crash while writing leaves the previous snapshot
+    fn write_snapshot(&self, path: &PathBuf) -> Result<()> {
+        let now = now_micros()?;
+        let map = self.map.read().unwrap();
+        let entries: BTreeMap<&serde_bytes::Bytes, &Entry> = map
+            .iter()
+            .filter(|(_, entry)| entry.is_live(now))
+            .map(|(key, entry)| (serde_bytes::Bytes::new(key), entry))
+            .collect();
+        let temp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_cbor::to_writer(&mut writer, &entries)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(path) = &self.snapshot {
+            if let Err(e) = self.write_snapshot(path) {
+                log::error!("writing the snapshot {:?} failed: {:?}", path, e);
+            }
+        }
+    }
+}
+
+fn key_not_found(key: &[u8]) -> KvsError {
+    KvsError::KeyNotFound {
+        key: String::from_utf8_lossy(key).to_string(),
+        backtrace: Backtrace::force_capture(),
+    }
+}
+
+fn now_micros() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
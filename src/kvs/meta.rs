@@ -0,0 +1,203 @@
+use std::backtrace::Backtrace;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{KvsError, Result};
+use super::{record, sstable};
+
+/// Name of the metadata file kept in the data directory.
+pub const META_FILE: &str = "kvs.meta";
+// marker of the engine written by the servers before the metadata file, in the same directory
+const LEGACY_ENGINE_FILE: &str = "last_engine.txt";
+
+// sled keeps the version of its own files, this only changes with the way keys are stored in it
+const SLED_FORMAT_VERSION: u16 = 1;
+const MEMORY_FORMAT_VERSION: u16 = 1;
+
+/// What the data directory holds, written by the first engine opened there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineMeta {
+    /// Name of the engine, as given to `get_engine_by_name`
+    pub engine: String,
+    /// Version of the on-disk format of the engine
+    pub format_version: u16,
+    /// Creation time of the directory, in seconds since the epoch
+    pub created_at: u64,
+    /// Time of the last clean shutdown, in seconds since the epoch
+    pub last_clean_shutdown: Option<u64>,
+    /// True from the opening of the engine to its clean shutdown
+    pub running: bool,
+    /// Engine a migration is copying the keys into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrating_to: Option<String>,
+}
+
+impl EngineMeta {
+    /// Read the metadata of `dir`, None if it has none yet.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<EngineMeta>> {
+        match File::open(dir.as_ref().join(META_FILE)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Name of the engine of `dir`, from its metadata or from the marker older versions left there.
+    pub fn engine_of(dir: impl AsRef<Path>) -> Result<Option<String>> {
+        let dir = dir.as_ref();
+        if let Some(meta) = EngineMeta::load(dir)? {
+            return Ok(Some(meta.engine));
+        }
+        match fs::read_to_string(dir.join(LEGACY_ENGINE_FILE)) {
+            Ok(engine) => Ok(Some(engine.trim().to_owned())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // written to a temporary file and renamed, so that a crash leaves either the old or the new metadata
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(META_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Version of the on-disk format written by the engine called `engine`.
+pub(crate) fn format_version(engine: &str) -> u16 {
+    match engine {
+        "kvs" => record::FORMAT_VERSION,
+        "lsm" => sstable::TABLE_VERSION,
+        "memory" => MEMORY_FORMAT_VERSION,
+        _ => SLED_FORMAT_VERSION,
+    }
+}
+
+/// Marks the directory as cleanly shut down once the last handle of the engine is dropped.
+pub(crate) struct MetaGuard {
+    dir: PathBuf,
+    // false for the target of a migration, the metadata still belongs to the source engine,
+    // and for a read-only open
+    owner: bool,
+}
+
+/// Check that `dir` holds the data of `engine` in a format it reads, and mark it as running.
+/// A new directory gets its metadata written, an existing one of another engine is refused,
+/// unless a migration is copying the keys into `engine`.
+pub(crate) fn open_meta(dir: &Path, engine: &str) -> Result<MetaGuard> {
+    let version = format_version(engine);
+    let meta = match EngineMeta::load(dir)? {
+        Some(meta) if meta.engine == engine => {
+            check_version(engine, &meta)?;
+            if meta.running {
+                log::warn!("{:?} was not shut down cleanly", dir);
+            }
+            // older formats are still read, and the engine only writes the current one from now on
+            EngineMeta {
+                format_version: version,
+                running: true,
+                ..meta
+            }
+        }
+        Some(meta) if meta.migrating_to.as_deref() == Some(engine) => {
+            return Ok(MetaGuard {
+                dir: dir.to_owned(),
+                owner: false,
+            })
+        }
+        Some(meta) => return Err(engine_mismatch(engine, &meta.engine)),
+        None => {
+            match EngineMeta::engine_of(dir)? {
+                Some(found) if found != engine => return Err(engine_mismatch(engine, &found)),
+                Some(_) => fs::remove_file(dir.join(LEGACY_ENGINE_FILE))?,
+                None => {}
+            }
+            EngineMeta {
+                engine: engine.to_owned(),
+                format_version: version,
+                created_at: now_secs()?,
+                last_clean_shutdown: None,
+                running: true,
+                migrating_to: None,
+            }
+        }
+    };
+    meta.save(dir)?;
+    Ok(MetaGuard {
+        dir: dir.to_owned(),
+        owner: true,
+    })
+}
+
+/// Check that `dir` holds the data of `engine` in a format it reads, like `open_meta` but without
+/// writing anything, for a read-only open.
+pub(crate) fn check_meta(dir: &Path, engine: &str) -> Result<MetaGuard> {
+    match EngineMeta::load(dir)? {
+        Some(meta) if meta.engine == engine => check_version(engine, &meta)?,
+        Some(meta) if meta.migrating_to.as_deref() == Some(engine) => {}
+        Some(meta) => return Err(engine_mismatch(engine, &meta.engine)),
+        None => match EngineMeta::engine_of(dir)? {
+            Some(found) if found != engine => return Err(engine_mismatch(engine, &found)),
+            _ => {}
+        },
+    }
+    Ok(MetaGuard {
+        dir: dir.to_owned(),
+        owner: false,
+    })
+}
+
+fn check_version(engine: &str, meta: &EngineMeta) -> Result<()> {
+    if meta.format_version > format_version(engine) {
+        return Err(KvsError::UnsupportedEngineFormat {
+            engine: engine.to_owned(),
+            version: meta.format_version,
+            backtrace: Backtrace::force_capture(),
+        });
+    }
+    Ok(())
+}
+
+impl MetaGuard {
+    fn mark_clean_shutdown(&self) -> Result<()> {
+        if let Some(meta) = EngineMeta::load(&self.dir)? {
+            EngineMeta {
+                last_clean_shutdown: Some(now_secs()?),
+                running: false,
+                ..meta
+            }
+            .save(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MetaGuard {
+    fn drop(&mut self) {
+        if self.owner {
+            if let Err(e) = self.mark_clean_shutdown() {
+                log::error!("marking {:?} as shut down failed: {:?}", self.dir, e);
+            }
+        }
+    }
+}
+
+fn engine_mismatch(engine: &str, found: &str) -> KvsError {
+    KvsError::EngineMismatch {
+        engine: engine.to_owned(),
+        found: found.to_owned(),
+        backtrace: Backtrace::force_capture(),
+    }
+}
+
+fn now_secs() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
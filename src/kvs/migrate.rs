@@ -0,0 +1,276 @@
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use super::engine::{get_engine_by_name, KvsEngine, WriteBatch};
+use super::error::{KvsError, Result};
+use super::meta::{format_version, EngineMeta};
+
+// progress of an unfinished migration, to resume it where it stopped
+const STATE_FILE: &str = "migrate.state";
+// keys copied per write batch, the state is saved after each batch
+const BATCH_SIZE: usize = 1000;
+
+/// Number of live keys, and checksum of every pair in key order, found in both engines after a migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Number of live keys
+    pub count: u64,
+    /// crc32 of the pairs, those without a ttl first, each in key order
+    pub checksum: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MigrationState {
+    from: String,
+    to: String,
+    // last key copied, None until the first batch is written
+    copied_up_to: Option<ByteBuf>,
+}
+
+/// Copy every live key of the `from` engine stored in `dir` into the `to` engine in the same directory,
+/// with its ttl, then record `to` as the engine of the directory in its metadata.
+///
+/// Nothing should write to `dir` meanwhile. The keys are copied in batches and the progress is saved
+/// after each one, so calling `migrate` again after an interruption goes on from the last batch.
+/// Once copied, both engines are scanned, their key counts and checksums should be the same, leaving
+/// out the keys that expired meanwhile. If they are not, the keys copied into `to` are removed and
+/// calling `migrate` again starts over. The files of `from` are left in `dir`.
+pub fn migrate(dir: impl AsRef<Path>, from: &str, to: &str) -> Result<MigrationReport> {
+    let dir = dir.as_ref();
+    let fail = |reason: String| KvsError::Migration {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        reason,
+        backtrace: Backtrace::force_capture(),
+    };
+    if from == to {
+        return Err(fail("the engines are the same".to_owned()));
+    }
+    if from == "memory" || to == "memory" {
+        return Err(fail("the memory engine keeps nothing on disk".to_owned()));
+    }
+
+    let state_path = dir.join(STATE_FILE);
+    let (state, resumed) = match read_state(&state_path)? {
+        Some(state) if state.from == from && state.to == to => (state, true),
+        Some(state) => {
+            return Err(fail(format!(
+                "a migration from {} to {} is not finished",
+                state.from, state.to
+            )))
+        }
+        None => (
+            MigrationState {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                copied_up_to: None,
+            },
+            false,
+        ),
+    };
+    let source = get_engine_by_name(from, dir)?;
+    // the metadata lets the target open the directory of the source
+    set_migrating_to(dir, Some(to))?;
+    let target = get_engine_by_name(to, dir)?;
+    if !resumed {
+        if target.scan_bytes(&[], None, Some(1))?.next().is_some() {
+            drop(target);
+            set_migrating_to(dir, None)?;
+            return Err(fail(format!("{} already holds keys in {:?}", to, dir)));
+        }
+        // saved before anything is copied, a migration interrupted in its first batch is resumed, not refused
+        write_state(&state_path, &state)?;
+    }
+
+    copy(&*source, &*target, state, &state_path)?;
+    let source_digest = digest(&*source)?;
+    let target_digest = digest(&*target)?;
+    // a key that expired while the engines were scanned may be missing from either of them
+    let scanned_at = SystemTime::now();
+    let expired: HashSet<&[u8]> = source_digest
+        .expiring
+        .iter()
+        .chain(&target_digest.expiring)
+        .filter(|(_, (_, expire_at))| *expire_at <= scanned_at)
+        .map(|(key, _)| key.as_slice())
+        .collect();
+    let expected = source_digest.report(&expired);
+    let report = target_digest.report(&expired);
+    if report != expected {
+        // the copy cannot be trusted, so it is undone rather than resumed by the next call
+        clear(&*target)?;
+        drop(target);
+        set_migrating_to(dir, None)?;
+        fs::remove_file(&state_path)?;
+        return Err(fail(format!(
+            "{} keys with checksum {:08x} copied, {} keys with checksum {:08x} expected, \
+             the copied keys are removed",
+            report.count, report.checksum, expected.count, expected.checksum
+        )));
+    }
+    drop(source);
+    drop(target);
+    if let Some(meta) = EngineMeta::load(dir)? {
+        EngineMeta {
+            engine: to.to_owned(),
+            format_version: format_version(to),
+            migrating_to: None,
+            ..meta
+        }
+        .save(dir)?;
+    }
+    fs::remove_file(&state_path)?;
+    Ok(report)
+}
+
+fn set_migrating_to(dir: &Path, engine: Option<&str>) -> Result<()> {
+    if let Some(meta) = EngineMeta::load(dir)? {
+        EngineMeta {
+            migrating_to: engine.map(str::to_owned),
+            ..meta
+        }
+        .save(dir)?;
+    }
+    Ok(())
+}
+
+fn copy(
+    source: &dyn KvsEngine,
+    target: &dyn KvsEngine,
+    mut state: MigrationState,
+    state_path: &Path,
+) -> Result<()> {
+    loop {
+        // the smallest key after the last one copied is that key followed by a zero byte
+        let start = match &state.copied_up_to {
+            Some(key) => [key.as_slice(), &[0]].concat(),
+            None => Vec::new(),
+        };
+        let pairs = source
+            .scan_bytes(&start, None, Some(BATCH_SIZE))?
+            .collect::<Result<Vec<_>>>()?;
+        let last_key = match pairs.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let done = pairs.len() < BATCH_SIZE;
+
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            match source.ttl_bytes(&key) {
+                Ok(None) => {
+                    batch.set(key, value);
+                }
+                Ok(Some(ttl)) => target.set_bytes_with_ttl(&key, &value, ttl)?,
+                // expired since the scan
+                Err(KvsError::KeyNotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        target.write_batch(batch)?;
+        state.copied_up_to = Some(ByteBuf::from(last_key));
+        write_state(state_path, &state)?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+// remove every key of `engine`, in batches
+fn clear(engine: &dyn KvsEngine) -> Result<()> {
+    loop {
+        let keys = engine
+            .scan_bytes(&[], None, Some(BATCH_SIZE))?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        engine.write_batch(batch)?;
+    }
+}
+
+/// The pairs of an engine, the ones with a ttl kept apart until it is known which expired during the scans.
+struct Digest {
+    count: u64,
+    hasher: crc32fast::Hasher,
+    // crc32 of the pair and expiry time of each key with a ttl
+    expiring: BTreeMap<Vec<u8>, (u32, SystemTime)>,
+}
+
+impl Digest {
+    fn report(&self, expired: &HashSet<&[u8]>) -> MigrationReport {
+        let mut count = self.count;
+        let mut hasher = self.hasher.clone();
+        for (key, (pair_checksum, _)) in &self.expiring {
+            if !expired.contains(key.as_slice()) {
+                count += 1;
+                hasher.update(&(key.len() as u64).to_le_bytes());
+                hasher.update(key);
+                hasher.update(&pair_checksum.to_le_bytes());
+            }
+        }
+        MigrationReport {
+            count,
+            checksum: hasher.finalize(),
+        }
+    }
+}
+
+fn digest(engine: &dyn KvsEngine) -> Result<Digest> {
+    let mut digest = Digest {
+        count: 0,
+        hasher: crc32fast::Hasher::new(),
+        expiring: BTreeMap::new(),
+    };
+    for pair in engine.scan_bytes(&[], None, None)? {
+        let (key, value) = pair?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(&key);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(&value);
+        let expire_at = match engine.ttl_bytes(&key) {
+            Ok(None) => {
+                digest.count += 1;
+                digest.hasher.combine(&hasher);
+                continue;
+            }
+            Ok(Some(ttl)) => SystemTime::now() + ttl,
+            // expired since the scan
+            Err(KvsError::KeyNotFound { .. }) => SystemTime::now(),
+            Err(e) => return Err(e),
+        };
+        digest.expiring.insert(key, (hasher.finalize(), expire_at));
+    }
+    Ok(digest)
+}
+
+fn read_state(path: &Path) -> Result<Option<MigrationState>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(serde_cbor::from_reader(BufReader::new(file))?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// written to a temporary file and renamed, so that a crash leaves either the old or the new state
+fn write_state(path: &Path, state: &MigrationState) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_cbor::to_vec(state)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io;
+
+/// Read-only memory map of a whole log file, for the files that are never written again.
+/// The file must not be truncated while it is mapped, removing it is fine.
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// the mapping is never written through, so it can be read from any thread
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map `file`, None if it is empty or if the platform has no memory maps.
+    #[cfg(unix)]
+    pub(crate) fn map(file: &File) -> io::Result<Option<Mmap>> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Mmap {
+            ptr: ptr as *const u8,
+            len,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn map(_file: &File) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+
+    /// The `len` bytes at `offset`, None if they are not all in the file.
+    pub(crate) fn get(&self, offset: u64, len: u64) -> Option<&[u8]> {
+        let end = offset.checked_add(len)?;
+        if end > self.len as u64 {
+            return None;
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(self.ptr, self.len) };
+        Some(&bytes[offset as usize..end as usize])
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
@@ -1,7 +1,18 @@
+pub mod cache;
 pub mod client;
+pub mod durability;
 pub mod engine;
 pub mod error;
+mod hint;
+pub mod lsm_engine;
+pub mod memory_engine;
+pub mod meta;
+pub mod migrate;
+mod mmap;
 pub mod protocol;
+mod record;
 pub mod server;
 pub mod sled_engine;
+pub mod snapshot;
+mod sstable;
 pub mod store;
@@ -1,18 +1,61 @@
-// for simplify reason, use json for client/server communication potocol
+// messages are encoded in cbor, keys and values are sent as cbor byte strings as they are
 extern crate serde;
 
 use std::backtrace::Backtrace;
+use std::io::{self, prelude::*};
+use std::time::Duration;
 
 use crate::KvsError;
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use super::engine::WriteBatch;
 use super::error::Result;
 
+/// Size of the big-endian length header in front of every frame.
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// Largest payload of a frame, a longer one is refused before anything is allocated for it.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
-    Get(String),
-    Set(String, String),
-    Remove(String),
+    Get(#[serde(with = "serde_bytes")] Vec<u8>),
+    Set(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+    ),
+    /// Set a value that expires after the duration
+    SetWithTtl(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        Duration,
+    ),
+    /// Time left before a key expires
+    Ttl(#[serde(with = "serde_bytes")] Vec<u8>),
+    Remove(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// Scan a range of keys, returning at most `limit` pairs if it is given
+    Scan(ScanRange, Option<usize>),
+    /// Apply all the operations of the batch atomically
+    Batch(WriteBatch),
+    /// Replace the value of a key if it is the expected one, None means absent
+    CompareAndSwap(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ScanRange {
+    /// Keys from the start included to the end excluded, or to the last key
+    Range(
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
+    /// Keys starting with a prefix
+    Prefix(#[serde(with = "serde_bytes")] Vec<u8>),
 }
 
 pub struct CommandResult(pub Result<Command>);
@@ -28,7 +71,7 @@ impl From<(String, String, Option<String>)> for CommandResult {
                         backtrace: Backtrace::force_capture(),
                     }));
                 }
-                command = Command::Get(key);
+                command = Command::Get(key.into_bytes());
             }
             "set" => {
                 if value.is_none() {
@@ -37,7 +80,7 @@ impl From<(String, String, Option<String>)> for CommandResult {
                         backtrace: Backtrace::force_capture(),
                     }));
                 }
-                command = Command::Set(key, value.unwrap());
+                command = Command::Set(key.into_bytes(), value.unwrap().into_bytes());
             }
             "rm" => {
                 if let Some(value) = value {
@@ -46,7 +89,7 @@ impl From<(String, String, Option<String>)> for CommandResult {
                         backtrace: Backtrace::force_capture(),
                     }));
                 }
-                command = Command::Remove(key);
+                command = Command::Remove(key.into_bytes());
             }
             _ => {
                 return CommandResult(Err(KvsError::UnexpectedCommand {
@@ -61,6 +104,77 @@ impl From<(String, String, Option<String>)> for CommandResult {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Success(Option<String>),
+    Success(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    /// Key-value pairs of a scan, in key order
+    Pairs(#[serde(with = "byte_pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    /// Whether a compare-and-swap changed the value
+    Swapped(bool),
+    /// Time left before a key expires, None if it never expires
+    Ttl(Option<Duration>),
     Error(String),
 }
+
+// serde_bytes does not reach into tuples, the pairs go through its borrowed and owned wrappers instead
+mod byte_pairs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            pairs
+                .iter()
+                .map(|(key, value)| (Bytes::new(key), Bytes::new(value))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pairs, D::Error> {
+        let pairs = Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect())
+    }
+}
+
+/// Write one message as a frame: a 4-byte big-endian payload length followed by the cbor payload.
+/// The writer is flushed so a buffered stream actually sends the frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let payload = serde_cbor::to_vec(message)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!("frame too large: {} bytes", payload.len()).into());
+    }
+    let len = payload.len() as u32;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one frame written by `write_frame`.
+/// Return None if the peer closed the stream cleanly before a new frame started,
+/// a truncated header or payload, or a length over `MAX_FRAME_SIZE`, is an error.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut header = [0; FRAME_HEADER_SIZE];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_SIZE {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!("frame too large: {} bytes", len).into());
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_cbor::from_slice(&payload)?))
+}
@@ -0,0 +1,397 @@
+// On-disk layout of the KvStore log files.
+//
+// Every N.db file starts with a segment header followed by records:
+//
+// segment: | magic "KVSLOG" (6) | format version u16 (2) | record | record | ...
+// record:  | crc32 u32 | key_len u32 | value_len u32 | tstamp u64 | expire_at u64 | command u8 | key | value |
+//
+// expire_at is the time in micros after which a set reads as absent, 0 if it never expires.
+// Version 1 records have no expire_at, such files are rewritten by `migrate_binary_segment`.
+// Keys and values are arbitrary bytes. All integers are little endian. The crc covers every byte of the record after the crc field,
+// so a torn write or a flipped bit is detected per record instead of breaking the whole file.
+//
+// The records of a write batch are written between a BatchBegin and a BatchCommit record,
+// both with an empty key and value. They are only applied once the commit has been read.
+// Files written before the binary format are bare json records, see `migrate_legacy_segment`.
+use super::error::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::backtrace::Backtrace;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::Path;
+
+pub(crate) const SEGMENT_MAGIC: &[u8; 6] = b"KVSLOG";
+/// Current version of the binary record format
+pub(crate) const FORMAT_VERSION: u16 = 2;
+pub(crate) const SEGMENT_HEADER_SIZE: u64 = 8;
+const RECORD_HEADER_SIZE: usize = 29;
+const V1_RECORD_HEADER_SIZE: usize = 21;
+
+fn record_header_size(version: u16) -> usize {
+    if version == 1 {
+        V1_RECORD_HEADER_SIZE
+    } else {
+        RECORD_HEADER_SIZE
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub(crate) enum Command {
+    Set,
+    Get,
+    Remove,
+    BatchBegin,
+    BatchCommit,
+}
+
+impl Command {
+    fn to_byte(&self) -> u8 {
+        match self {
+            Command::Set => 1,
+            Command::Get => 2,
+            Command::Remove => 3,
+            Command::BatchBegin => 4,
+            Command::BatchCommit => 5,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Command> {
+        match byte {
+            1 => Some(Command::Set),
+            2 => Some(Command::Get),
+            3 => Some(Command::Remove),
+            4 => Some(Command::BatchBegin),
+            5 => Some(Command::BatchCommit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Record {
+    pub(crate) command: Command,
+    pub(crate) tstamp: u64,
+    pub(crate) expire_at: u64,
+    pub(crate) key: Vec<u8>,
+    pub(crate) value: Vec<u8>,
+}
+
+// serde is only kept to read logs written in the legacy json format, which only held strings
+#[derive(Deserialize)]
+struct LegacyRecord {
+    command: Command,
+    tstamp: u64,
+    key: String,
+    value: String,
+}
+
+impl From<LegacyRecord> for Record {
+    fn from(record: LegacyRecord) -> Record {
+        Record {
+            command: record.command,
+            tstamp: record.tstamp,
+            expire_at: 0,
+            key: record.key.into_bytes(),
+            value: record.value.into_bytes(),
+        }
+    }
+}
+
+impl Record {
+    /// Serialize the record with its header and checksum.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + self.key.len() + self.value.len());
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.tstamp.to_le_bytes());
+        buf.extend_from_slice(&self.expire_at.to_le_bytes());
+        buf.push(self.command.to_byte());
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Deserialize a whole record, as written by `encode`, and verify its checksum.
+    /// The error is the reason of the corruption, the caller knows where the record is.
+    pub(crate) fn decode(buf: &[u8]) -> std::result::Result<Record, String> {
+        if buf.len() < RECORD_HEADER_SIZE {
+            return Err(format!("record header too short: {} bytes", buf.len()));
+        }
+        let header = RecordHeader::parse(&buf[..RECORD_HEADER_SIZE], FORMAT_VERSION);
+        if buf.len() != RECORD_HEADER_SIZE + header.payload_len() {
+            return Err(format!(
+                "record length mismatch: expect {} bytes, got {}",
+                RECORD_HEADER_SIZE + header.payload_len(),
+                buf.len()
+            ));
+        }
+        header.into_record(&buf[RECORD_HEADER_SIZE..])
+    }
+}
+
+struct RecordHeader {
+    crc: u32,
+    key_len: u32,
+    value_len: u32,
+    tstamp: u64,
+    expire_at: u64,
+    command: u8,
+    // bytes covered by the crc inside the header
+    checked: [u8; RECORD_HEADER_SIZE - 4],
+    checked_len: usize,
+}
+
+impl RecordHeader {
+    /// `buf` holds the whole header of a record written in format `version`.
+    fn parse(buf: &[u8], version: u16) -> RecordHeader {
+        let u32_at = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+        let header_size = record_header_size(version);
+        let mut checked = [0; RECORD_HEADER_SIZE - 4];
+        checked[..header_size - 4].copy_from_slice(&buf[4..header_size]);
+        let expire_at = if version == 1 { 0 } else { u64_at(20) };
+        RecordHeader {
+            crc: u32_at(0),
+            key_len: u32_at(4),
+            value_len: u32_at(8),
+            tstamp: u64_at(12),
+            expire_at,
+            command: buf[header_size - 1],
+            checked,
+            checked_len: header_size - 4,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        self.key_len as usize + self.value_len as usize
+    }
+
+    fn into_record(self, payload: &[u8]) -> std::result::Result<Record, String> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.checked[..self.checked_len]);
+        hasher.update(payload);
+        if hasher.finalize() != self.crc {
+            return Err("checksum mismatch".to_string());
+        }
+        let command = Command::from_byte(self.command)
+            .ok_or_else(|| format!("unknown command {}", self.command))?;
+        let (key, value) = payload.split_at(self.key_len as usize);
+        Ok(Record {
+            command,
+            tstamp: self.tstamp,
+            expire_at: self.expire_at,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        })
+    }
+}
+
+/// Read records one by one from a binary segment, keeping track of their positions.
+pub(crate) struct RecordReader<R: Read> {
+    reader: R,
+    file_id: u64,
+    pos: u64,
+    file_len: u64,
+    version: u16,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// `reader` must be positioned right after the segment header.
+    pub(crate) fn new(reader: R, file_id: u64, file_len: u64) -> Self {
+        Self::with_version(reader, file_id, file_len, FORMAT_VERSION)
+    }
+
+    /// Read records written in an older binary format `version`.
+    pub(crate) fn with_version(reader: R, file_id: u64, file_len: u64, version: u16) -> Self {
+        RecordReader {
+            reader,
+            file_id,
+            pos: SEGMENT_HEADER_SIZE,
+            file_len,
+            version,
+        }
+    }
+
+    /// Position of the next record, which is also the end of the last valid record.
+    pub(crate) fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Return the position, size and content of the next record, or None at the end of file.
+    pub(crate) fn next_record(&mut self) -> Result<Option<(u64, u64, Record)>> {
+        let header_size = record_header_size(self.version);
+        let mut header = [0; RECORD_HEADER_SIZE];
+        let n = read_full(&mut self.reader, &mut header[..header_size])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        if n < header_size {
+            return Err(self.corrupted(format!("truncated record header: {} bytes", n)));
+        }
+        let header = RecordHeader::parse(&header, self.version);
+        // a corrupted length must not make us allocate more than the file holds
+        let remaining = self.file_len.saturating_sub(self.pos + header_size as u64);
+        if header.payload_len() as u64 > remaining {
+            return Err(self.corrupted(format!(
+                "truncated record payload: expect {} bytes, got {}",
+                header.payload_len(),
+                remaining
+            )));
+        }
+        let mut payload = vec![0; header.payload_len()];
+        self.reader.read_exact(&mut payload)?;
+        let record = header
+            .into_record(&payload)
+            .map_err(|reason| self.corrupted(reason))?;
+        let pos = self.pos;
+        let size = (header_size + payload.len()) as u64;
+        self.pos += size;
+        Ok(Some((pos, size, record)))
+    }
+
+    fn corrupted(&self, reason: String) -> KvsError {
+        KvsError::Corrupted {
+            file_id: self.file_id,
+            offset: self.pos,
+            reason,
+            backtrace: Backtrace::force_capture(),
+        }
+    }
+}
+
+// like read_exact, but a short read at the end of file is not an error
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+pub(crate) fn write_segment_header(file: &mut impl Write) -> Result<()> {
+    file.write_all(SEGMENT_MAGIC)?;
+    file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) enum SegmentFormat {
+    /// Empty file that has not got its segment header yet
+    Empty,
+    Binary,
+    /// Binary format of an older version
+    OldBinary(u16),
+    LegacyJson,
+}
+
+/// Check the segment header of a log file and return its format.
+pub(crate) fn read_segment_format(file: &File, file_id: u64) -> Result<SegmentFormat> {
+    let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+    let mut reader = file;
+    reader.seek(io::SeekFrom::Start(0))?;
+    let n = read_full(&mut reader, &mut header)?;
+    if n == 0 {
+        return Ok(SegmentFormat::Empty);
+    }
+    let magic_len = n.min(SEGMENT_MAGIC.len());
+    if header[..magic_len] == SEGMENT_MAGIC[..magic_len] {
+        if n < SEGMENT_HEADER_SIZE as usize {
+            return Err(KvsError::Corrupted {
+                file_id,
+                offset: 0,
+                reason: "truncated segment header".to_string(),
+                backtrace: Backtrace::force_capture(),
+            });
+        }
+        let version = u16::from_le_bytes([header[6], header[7]]);
+        return match version {
+            FORMAT_VERSION => Ok(SegmentFormat::Binary),
+            1 => Ok(SegmentFormat::OldBinary(version)),
+            _ => Err(KvsError::UnsupportedFormat {
+                file_id,
+                version,
+                backtrace: Backtrace::force_capture(),
+            }),
+        };
+    }
+    if header[0] == b'{' {
+        return Ok(SegmentFormat::LegacyJson);
+    }
+    Err(KvsError::Corrupted {
+        file_id,
+        offset: 0,
+        reason: "unknown segment header".to_string(),
+        backtrace: Backtrace::force_capture(),
+    })
+}
+
+/// Rewrite a log file of json records into the binary format.
+/// The new file is written beside the old one and then renamed over it,
+/// so a crash during migration leaves the json file untouched.
+pub(crate) fn migrate_legacy_segment(path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("db.migrating");
+    {
+        let reader = BufReader::new(File::open(path)?);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_segment_header(&mut writer)?;
+        for record in serde_json::Deserializer::from_reader(reader).into_iter::<LegacyRecord>() {
+            writer.write_all(&Record::from(record?).encode())?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Rewrite a log file of an older binary format `version` into the current one, like
+/// `migrate_legacy_segment`. If `truncate_torn` is set, a torn tail is dropped instead of failing,
+/// which is only safe for the newest file.
+pub(crate) fn migrate_binary_segment(
+    path: &Path,
+    file_id: u64,
+    version: u16,
+    truncate_torn: bool,
+) -> Result<()> {
+    let tmp_path = path.with_extension("db.migrating");
+    {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(io::SeekFrom::Start(SEGMENT_HEADER_SIZE))?;
+        let mut records = RecordReader::with_version(reader, file_id, file_len, version);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_segment_header(&mut writer)?;
+        loop {
+            match records.next_record() {
+                Ok(Some((_, _, record))) => writer.write_all(&record.encode())?,
+                Ok(None) => break,
+                Err(KvsError::Corrupted { offset, reason, .. }) if truncate_torn => {
+                    log::warn!(
+                        "drop torn tail of {}.db after offset {} ({})",
+                        file_id,
+                        offset,
+                        reason
+                    );
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -4,33 +4,33 @@ use crate::{KvStore, SledKvsEngine};
 use super::super::thread_pool::*;
 use super::error::Result;
 use slog::Logger;
-use std::io::{self, prelude::*};
+use std::io::{self, BufReader, BufWriter};
 use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 
-use super::engine::KvsEngine;
+use super::engine::{BytesScanIter, KvsEngine, KvsEngineFactory};
 
-use super::protocol::{Command, Response};
+use super::protocol::{read_frame, write_frame, Command, Response, ScanRange};
 
 pub trait IKvsServer {
     fn run(&self) -> Result<()>;
     fn close(&self);
 }
 
-pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
+pub struct KvsServer<T: ThreadPool> {
     logger: Logger,
     listener: TcpListener,
-    engine: E,
+    engine: Arc<dyn KvsEngine>,
     pool: T,
     is_close: Arc<AtomicBool>,
     connetion_num: Arc<AtomicUsize>,
 }
 
-pub fn get_kvs_server_by_config<E: KvsEngine, T: ThreadPool>(
+pub fn get_kvs_server_by_config<E: KvsEngineFactory, T: ThreadPool>(
     num_thread: u32,
     ip_port: (std::net::IpAddr, u16),
-) -> KvsServer<E, T> {
+) -> KvsServer<T> {
     let root_logger: slog::Logger = get_root_logger("kvs-server".to_string());
     let pool = T::new(num_thread).unwrap();
     KvsServer::new(ip_port, E::new().unwrap(), pool, root_logger).unwrap()
@@ -59,7 +59,7 @@ pub fn get_kvs_client_by_config_dyn(
     }
 }
 
-impl<E: KvsEngine, T: ThreadPool> IKvsServer for KvsServer<E, T> {
+impl<T: ThreadPool> IKvsServer for KvsServer<T> {
     fn run(&self) -> Result<()> {
         // if not using non-blocking IO, then thread maybe be block here and don't know server should be closed
         self.listener.set_nonblocking(true)?;
@@ -104,10 +104,20 @@ impl<E: KvsEngine, T: ThreadPool> IKvsServer for KvsServer<E, T> {
     }
 }
 
-impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
+impl<T: ThreadPool> KvsServer<T> {
     pub fn new(
         ip_port: (std::net::IpAddr, u16),
-        engine: E,
+        engine: impl KvsEngine,
+        pool: T,
+        logger: Logger,
+    ) -> Result<Self> {
+        KvsServer::with_shared_engine(ip_port, Arc::new(engine), pool, logger)
+    }
+
+    /// Serve an engine that may be chosen at runtime, see `get_engine_by_name`.
+    pub fn with_shared_engine(
+        ip_port: (std::net::IpAddr, u16),
+        engine: Arc<dyn KvsEngine>,
         pool: T,
         logger: Logger,
     ) -> Result<Self> {
@@ -128,38 +138,72 @@ impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
 
 pub fn serve(
     logger: Logger,
-    engine: impl KvsEngine,
+    engine: Arc<dyn KvsEngine>,
     stream: TcpStream,
     is_close: Arc<AtomicBool>,
 ) -> Result<()> {
-    let mut buf = vec![0; 1024];
-    loop {
-        let mut stream = stream.try_clone()?;
-        let n = stream.read(&mut buf)?;
-        if n == 0 {
-            break;
-        }
-        let command: Command = serde_json::from_slice(&buf[..n])?;
+    // a frame may be split across several reads or share one read with the next frame,
+    // so always go through the buffered reader instead of reading the socket directly
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    while let Some(command) = read_frame::<_, Command>(&mut reader)? {
         debug!(logger, "recv request"; "request" => format!("{:?}", command));
         let response = match command {
             // log error but not stop server
             Command::Get(key) => {
-                let value = engine.get(key)?;
+                let value = engine.get_bytes(&key)?;
                 Response::Success(value)
             }
             Command::Set(key, value) => {
-                engine.set(key, value)?;
+                engine.set_bytes(&key, &value)?;
                 Response::Success(None)
             }
-            Command::Remove(key) => match engine.remove(key) {
+            Command::SetWithTtl(key, value, ttl) => {
+                match engine.set_bytes_with_ttl(&key, &value, ttl) {
+                    Ok(()) => Response::Success(None),
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Command::Ttl(key) => match engine.ttl_bytes(&key) {
+                Ok(ttl) => Response::Ttl(ttl),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Command::Remove(key) => match engine.remove_bytes(&key) {
                 Ok(()) => Response::Success(None),
                 // Err(KvsError::KeyNotFound{key: _, backtrace: _}) => Response::Error("Key not foundddd".to_string()),
                 Err(e) => Response::Error(e.to_string()),
             },
+            Command::Scan(range, limit) => {
+                let pairs = match range {
+                    ScanRange::Range(start, end) => {
+                        engine.scan_bytes(&start, end.as_deref(), limit)
+                    }
+                    ScanRange::Prefix(prefix) => {
+                        engine
+                            .scan_prefix_bytes(&prefix)
+                            .map(|pairs| -> BytesScanIter {
+                                Box::new(pairs.take(limit.unwrap_or(usize::MAX)))
+                            })
+                    }
+                };
+                match pairs.and_then(|pairs| pairs.collect::<Result<_>>()) {
+                    Ok(pairs) => Response::Pairs(pairs),
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Command::Batch(batch) => match engine.write_batch(batch) {
+                Ok(()) => Response::Success(None),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Command::CompareAndSwap(key, expected, new) => {
+                match engine.compare_and_swap_bytes(&key, expected.as_deref(), new.as_deref()) {
+                    Ok(swapped) => Response::Swapped(swapped),
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
         };
-        let response_serialized = serde_json::to_string(&response)?;
-        debug!(logger, "send response"; "response" => response_serialized.to_string());
-        stream.write_all(response_serialized.as_bytes())?;
+        debug!(logger, "send response"; "response" => format!("{:?}", response));
+        write_frame(&mut writer, &response)?;
         if is_close.load(Ordering::SeqCst) {
             stream.shutdown(Shutdown::Both)?;
             break;
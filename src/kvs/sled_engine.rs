@@ -1,55 +1,312 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use std::backtrace::Backtrace;
-use std::path::PathBuf;
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sled::transaction::{TransactionError, Transactional};
 
 use crate::KvsError;
 
-use super::engine::KvsEngine;
+use super::durability::Durability;
+use super::engine::{BatchOp, BytesScanIter, KvsEngine, KvsEngineFactory, WriteBatch};
 use super::error::Result;
+use super::meta::{open_meta, MetaGuard};
+use super::snapshot::{CopiedSnapshot, KvsSnapshot};
+// expire_at in micros of the keys set with a ttl is kept in a tree of its own, keyed like the values,
+// and changed in the same transaction as the value
+const EXPIRY_TREE: &str = "expiry";
+
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: sled::Db,
+    expiry: sled::Tree,
+    meta: Arc<MetaGuard>,
+    durability: Durability,
+    // writes since the last flush, shared by the clones
+    unflushed: Arc<AtomicU64>,
+    // read-locked by every write, sled has no point-in-time reads so a snapshot write-locks it while it copies
+    writes: Arc<RwLock<()>>,
 }
 
-impl KvsEngine for SledKvsEngine {
+impl SledKvsEngine {
+    /// Open the sled database at `path`, flushing its log according to `durability`.
+    /// `Interval` is left to the background flusher of sled, and so is `OsBuffered` with its default
+    /// period of 500ms: sled buffers the writes in the process, a crash loses them until flushed.
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let meta = open_meta(&path, "sled")?;
+        let db = open_db(&path, durability)?;
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        Ok(SledKvsEngine {
+            db,
+            expiry,
+            meta: Arc::new(meta),
+            durability,
+            unflushed: Arc::new(AtomicU64::new(0)),
+            writes: Arc::new(RwLock::new(())),
+        })
+    }
+
+    /// Flush the log once the durability calls for it, after a write.
+    fn flush_write(&self) -> Result<()> {
+        let unflushed = self.unflushed.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.durability.needs_sync(unflushed) {
+            self.unflushed.store(0, Ordering::SeqCst);
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write `value`, or remove the key if it is None, with the given expiry.
+    fn write(&self, key: &[u8], value: Option<&[u8]>, expire_at: Option<u64>) -> Result<()> {
+        let _writes = self.writes.read().unwrap();
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                match value {
+                    Some(value) => db.insert(key, value)?,
+                    None => db.remove(key)?,
+                };
+                match expire_at {
+                    Some(expire_at) => expiry.insert(key, &expire_at.to_be_bytes())?,
+                    None => expiry.remove(key)?,
+                };
+                Ok(())
+            })
+            .map_err(storage_error)?;
+        self.flush_write()
+    }
+}
+
+impl KvsEngineFactory for SledKvsEngine {
     fn new() -> Result<Self> {
-        let db = sled::open(".")?;
-        Ok(SledKvsEngine { db })
+        SledKvsEngine::open(".")
     }
 
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let db = sled::open(path.into())?;
-        Ok(SledKvsEngine { db })
+        SledKvsEngine::open_with_durability(path, Durability::Always)
     }
+}
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, &*value)?;
-        self.db.flush()?;
-        Ok(())
+impl KvsEngine for SledKvsEngine {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write(key, Some(value), None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expire_at = now_micros()?.saturating_add(ttl.as_micros() as u64);
+        self.write(key, Some(value), Some(expire_at))
+    }
+
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let now = now_micros()?;
+        match live_entry(&self.db, &self.expiry, key, now)? {
+            Some((_, expire_at)) => {
+                Ok(expire_at.map(|expire_at| Duration::from_micros(expire_at - now)))
+            }
+            None => Err(KvsError::KeyNotFound {
+                key: String::from_utf8_lossy(key).to_string(),
+                backtrace: Backtrace::force_capture(),
+            }),
+        }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let result = self
-            .db
-            .get(key)?
-            .map(|value| String::from_utf8_lossy(&value).to_string());
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = live_entry(&self.db, &self.expiry, key, now_micros()?)?
+            .map(|(value, _)| value.to_vec());
 
         Ok(result)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(&key)?.ok_or(KvsError::KeyNotFound {
-            key,
-            backtrace: Backtrace::force_capture(),
-        })?;
-        self.db.flush()?;
-        Ok(())
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let now = now_micros()?;
+        let _writes = self.writes.read().unwrap();
+        let removed = (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                let expire_at = expiry.remove(key)?.map(|v| decode_expire_at(&v));
+                let removed = db.remove(key)?.is_some()
+                    && !matches!(expire_at, Some(expire_at) if expire_at <= now);
+                Ok(removed)
+            })
+            .map_err(storage_error)?;
+        if !removed {
+            return Err(KvsError::KeyNotFound {
+                key: String::from_utf8_lossy(key).to_string(),
+                backtrace: Backtrace::force_capture(),
+            });
+        }
+        self.flush_write()
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        // every key written by the batch loses its ttl
+        let mut expiry_batch = sled::Batch::default();
+        for op in batch.into_ops() {
+            let key = match op {
+                BatchOp::Set(key, value) => {
+                    sled_batch.insert(key.as_slice(), value);
+                    key
+                }
+                BatchOp::Remove(key) => {
+                    sled_batch.remove(key.as_slice());
+                    key
+                }
+            };
+            expiry_batch.remove(key);
+        }
+        let _writes = self.writes.read().unwrap();
+        (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                db.apply_batch(&sled_batch)?;
+                expiry.apply_batch(&expiry_batch)?;
+                Ok(())
+            })
+            .map_err(storage_error)?;
+        self.flush_write()
     }
 
-    fn clone(&self) -> Self {
-        SledKvsEngine {
-            db: self.db.clone(),
+    /// An expired value counts as absent, so the value and its expiry are compared in one transaction.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let now = now_micros()?;
+        let _writes = self.writes.read().unwrap();
+        let swapped = (&*self.db, &self.expiry)
+            .transaction(|(db, expiry)| {
+                let expire_at = expiry.get(key)?.map(|v| decode_expire_at(&v));
+                let current = match expire_at {
+                    Some(expire_at) if expire_at <= now => None,
+                    _ => db.get(key)?,
+                };
+                if current.as_deref() != expected {
+                    return Ok(false);
+                }
+                match new {
+                    Some(value) => db.insert(key, value)?,
+                    None => db.remove(key)?,
+                };
+                expiry.remove(key)?;
+                Ok(true)
+            })
+            .map_err(storage_error)?;
+        if swapped {
+            self.flush_write()?;
+        }
+        Ok(swapped)
+    }
+
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let pairs = self.db.range::<&[u8], _>((Bound::Included(start), end));
+        Ok(Box::new(
+            skip_expired(pairs, self.expiry.clone(), now_micros()?)
+                .take(limit.unwrap_or(usize::MAX)),
+        ))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> Result<BytesScanIter> {
+        let pairs = self.db.scan_prefix(prefix);
+        Ok(Box::new(skip_expired(
+            pairs,
+            self.expiry.clone(),
+            now_micros()?,
+        )))
+    }
+
+    /// Every live pair is copied while the writes through this engine are held back.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let _writes = self.writes.write().unwrap();
+        let pairs = skip_expired(self.db.iter(), self.expiry.clone(), now_micros()?)
+            .collect::<Result<_>>()?;
+        Ok(Box::new(CopiedSnapshot::new(pairs)))
+    }
+}
+
+/// Read the value of `key` and its expiry, if it has not expired by `now`.
+fn live_entry(
+    db: &sled::Tree,
+    expiry: &sled::Tree,
+    key: &[u8],
+    now: u64,
+) -> Result<Option<(sled::IVec, Option<u64>)>> {
+    (db, expiry)
+        .transaction(|(db, expiry)| {
+            let expire_at = expiry.get(key)?.map(|v| decode_expire_at(&v));
+            if matches!(expire_at, Some(expire_at) if expire_at <= now) {
+                return Ok(None);
+            }
+            Ok(db.get(key)?.map(|value| (value, expire_at)))
+        })
+        .map_err(storage_error)
+}
+
+fn skip_expired(
+    pairs: sled::Iter,
+    expiry: sled::Tree,
+    now: u64,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+    pairs.filter_map(move |pair| {
+        let (key, value) = match pair {
+            Ok(pair) => pair,
+            Err(e) => return Some(Err(e.into())),
+        };
+        match expiry.get(&key) {
+            Ok(Some(expire_at)) if decode_expire_at(&expire_at) <= now => None,
+            Ok(_) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Err(e) => Some(Err(e.into())),
+        }
+    })
+}
+
+fn decode_expire_at(value: &[u8]) -> u64 {
+    u64::from_be_bytes(value.try_into().unwrap_or_default())
+}
+
+// the transactions here never abort, only the storage can fail
+fn storage_error(e: TransactionError<()>) -> KvsError {
+    match e {
+        TransactionError::Storage(e) => e.into(),
+        TransactionError::Abort(()) => unreachable!("transaction aborted"),
+    }
+}
+
+fn now_micros() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros() as u64)
+}
+
+// The background threads of a dropped `sled::Db` release the lock of the directory a moment
+// after the last handle is gone, opening it again right away is retried for a while.
+fn open_db(path: &Path, durability: Durability) -> Result<sled::Db> {
+    let mut config = sled::Config::new().path(path);
+    if let Some(interval) = durability.interval() {
+        config = config.flush_every_ms(Some(interval.as_millis() as u64));
+    }
+    let mut retries = 50;
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(e)) if retries > 0 && e.to_string().contains("acquire lock") => {
+                retries -= 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => return Ok(result?),
         }
     }
 }
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::engine::{BytesScanIter, ScanIter};
+use super::error::Result;
+
+/// A read-only view of an engine at the point in time `KvsEngine::snapshot` was called.
+/// Writes made after that are not seen, and neither is any part of a write batch applied later,
+/// however long the snapshot is kept. A key that expires after the snapshot is taken stays readable in it.
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryKvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// let engine = MemoryKvsEngine::default();
+/// engine.set("key1", "value1")?;
+/// let snapshot = engine.snapshot()?;
+/// engine.set("key1", "value2")?;
+/// assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+/// assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub trait KvsSnapshot: Send + Sync {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Scan the keys from `start` included to `end` excluded, like `KvsEngine::scan_bytes`.
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter>;
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    fn scan(&self, start: &str, end: Option<&str>, limit: Option<usize>) -> Result<ScanIter> {
+        let pairs = self.scan_bytes(start.as_bytes(), end.map(str::as_bytes), limit)?;
+        Ok(Box::new(pairs.map(|pair| {
+            pair.and_then(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        })))
+    }
+}
+
+/// Snapshot of the engines that have no point-in-time reads of their own: the live pairs
+/// are copied once, while the engine holds its writes back.
+pub(crate) struct CopiedSnapshot {
+    pairs: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl CopiedSnapshot {
+    pub(crate) fn new(pairs: BTreeMap<Vec<u8>, Vec<u8>>) -> CopiedSnapshot {
+        CopiedSnapshot {
+            pairs: Arc::new(pairs),
+        }
+    }
+}
+
+impl KvsSnapshot for CopiedSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(key).cloned())
+    }
+
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        let pairs = self.pairs.clone();
+        let keys = scan_keys(start, end, limit);
+        Ok(Box::new(
+            keys.over(move |bound| {
+                pairs
+                    .range::<Vec<u8>, _>((bound, Bound::Unbounded))
+                    .next()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+            })
+            .map(Ok),
+        ))
+    }
+}
+
+/// Position of a lazy scan of a snapshot, which looks up the next key at every step
+/// so that the iterator only holds the snapshot and not a borrow of it.
+pub(crate) struct ScanKeys {
+    next: Bound<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    remaining: usize,
+}
+
+pub(crate) fn scan_keys(start: &[u8], end: Option<&[u8]>, limit: Option<usize>) -> ScanKeys {
+    ScanKeys {
+        next: Bound::Included(start.to_vec()),
+        end: end.map(<[u8]>::to_vec),
+        remaining: limit.unwrap_or(usize::MAX),
+    }
+}
+
+impl ScanKeys {
+    /// Iterate over the entries `first` returns, given the bound of the next key to return.
+    pub(crate) fn over<T, F>(mut self, mut first: F) -> impl Iterator<Item = (Vec<u8>, T)> + Send
+    where
+        T: Send,
+        F: FnMut(Bound<&Vec<u8>>) -> Option<(Vec<u8>, T)> + Send,
+    {
+        std::iter::from_fn(move || {
+            if self.remaining == 0 {
+                return None;
+            }
+            match first(self.next.as_ref()) {
+                Some((key, value)) if !matches!(&self.end, Some(end) if key >= *end) => {
+                    self.next = Bound::Excluded(key.clone());
+                    self.remaining -= 1;
+                    Some((key, value))
+                }
+                _ => {
+                    self.remaining = 0;
+                    None
+                }
+            }
+        })
+    }
+}
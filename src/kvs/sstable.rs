@@ -0,0 +1,495 @@
+// Sorted table files of the lsm engine.
+//
+// N.sst holds entries in increasing key order, grouped in blocks of about BLOCK_SIZE bytes:
+//
+// table:  | block | block | ... | index | bloom | footer |
+// block:  | entry | entry | ... | crc32 u32 |
+// entry:  | key_len u32 | value_len u32 | expire_at u64 | kind u8 | key | value |
+// index:  | first_key_len u32 | first_key | block_count u32 | handle | handle | ... | crc32 u32 |
+// handle: | last_key_len u32 | offset u64 | size u32 | last_key |
+// bloom:  | hash_count u32 | bits ... | crc32 u32 |
+// footer: | index_offset u64 | index_len u64 | bloom_offset u64 | bloom_len u64 | magic "KVSSST" (6) | version u16 |
+//
+// kind is 1 for a value and 0 for a tombstone, which hides the key in older tables.
+// expire_at is the time in micros after which the value reads as absent, 0 if it never expires.
+// All integers are little endian. The index and the bloom filter are kept in memory,
+// a lookup reads at most one block, and none when the bloom filter rules the key out.
+use super::error::{KvsError, Result};
+use super::store::read_exact_at;
+use std::backtrace::Backtrace;
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+const TABLE_MAGIC: &[u8; 6] = b"KVSSST";
+pub(crate) const TABLE_VERSION: u16 = 1;
+const FOOTER_SIZE: usize = 40;
+const ENTRY_HEADER_SIZE: usize = 17;
+const BLOCK_SIZE: usize = 4 * 1024;
+const BLOOM_BITS_PER_KEY: u64 = 10;
+const BLOOM_HASH_COUNT: u32 = 7;
+
+/// The latest write of a key, a value or a tombstone.
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    /// None for a tombstone
+    pub(crate) value: Option<Vec<u8>>,
+    pub(crate) expire_at: u64,
+}
+
+impl Entry {
+    /// Whether the entry holds a value that has not expired by `now`.
+    pub(crate) fn is_live(&self, now: u64) -> bool {
+        self.value.is_some() && (self.expire_at == 0 || self.expire_at > now)
+    }
+
+    /// Size of the entry in a block.
+    pub(crate) fn encoded_len(&self, key: &[u8]) -> usize {
+        ENTRY_HEADER_SIZE + key.len() + self.value.as_ref().map_or(0, Vec::len)
+    }
+}
+
+pub(crate) fn table_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", file_id))
+}
+
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+}
+
+/// Write a table, entries must be added in increasing key order.
+pub(crate) struct TableBuilder {
+    dir: PathBuf,
+    file_id: u64,
+    writer: BufWriter<File>,
+    block: Vec<u8>,
+    block_last_key: Vec<u8>,
+    first_key: Option<Vec<u8>>,
+    offset: u64,
+    index: Vec<BlockHandle>,
+    // two hashes of every key, the bloom filter is sized once all keys are known
+    key_hashes: Vec<(u32, u32)>,
+}
+
+impl TableBuilder {
+    pub(crate) fn new(dir: &Path, file_id: u64) -> Result<TableBuilder> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(table_path(dir, file_id))?;
+        Ok(TableBuilder {
+            dir: dir.to_path_buf(),
+            file_id,
+            writer: BufWriter::new(file),
+            block: Vec::with_capacity(BLOCK_SIZE * 2),
+            block_last_key: Vec::new(),
+            first_key: None,
+            offset: 0,
+            index: Vec::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub(crate) fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        let value = entry.value.as_deref().unwrap_or_default();
+        self.block
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.block
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(&entry.expire_at.to_le_bytes());
+        self.block.push(entry.value.is_some() as u8);
+        self.block.extend_from_slice(key);
+        self.block.extend_from_slice(value);
+        self.block_last_key.clear();
+        self.block_last_key.extend_from_slice(key);
+        self.key_hashes.push(bloom_hashes(key));
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, including the pending block.
+    pub(crate) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let crc = crc32fast::hash(&self.block);
+        self.block.extend_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.block_last_key.clone(),
+            offset: self.offset,
+            size: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write the index, the bloom filter and the footer, sync the file and open it for reading.
+    pub(crate) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let first_key = self.first_key.take().unwrap_or_default();
+        let mut index = Vec::new();
+        index.extend_from_slice(&(first_key.len() as u32).to_le_bytes());
+        index.extend_from_slice(&first_key);
+        index.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for handle in &self.index {
+            index.extend_from_slice(&(handle.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.size.to_le_bytes());
+            index.extend_from_slice(&handle.last_key);
+        }
+        let crc = crc32fast::hash(&index);
+        index.extend_from_slice(&crc.to_le_bytes());
+
+        let mut bloom = Bloom::new(self.key_hashes.len() as u64).encode(&self.key_hashes);
+        let crc = crc32fast::hash(&bloom);
+        bloom.extend_from_slice(&crc.to_le_bytes());
+
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&bloom)?;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&bloom_offset.to_le_bytes())?;
+        self.writer.write_all(&(bloom.len() as u64).to_le_bytes())?;
+        self.writer.write_all(TABLE_MAGIC)?;
+        self.writer.write_all(&TABLE_VERSION.to_le_bytes())?;
+        self.writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Table::open(&self.dir, self.file_id)
+    }
+}
+
+/// An immutable table file, with its index and bloom filter loaded.
+/// Once marked obsolete, the file is removed when the last reference to the table is dropped.
+pub(crate) struct Table {
+    pub(crate) file_id: u64,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    first_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    pub(crate) fn open(dir: &Path, file_id: u64) -> Result<Table> {
+        let path = table_path(dir, file_id);
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let corrupted = |reason: &str| KvsError::CorruptedTable {
+            file_id,
+            reason: reason.to_string(),
+            backtrace: Backtrace::force_capture(),
+        };
+        if size < FOOTER_SIZE as u64 {
+            return Err(corrupted("truncated footer"));
+        }
+        let mut footer = [0; FOOTER_SIZE];
+        read_exact_at(&file, &mut footer, size - FOOTER_SIZE as u64)?;
+        if &footer[32..38] != TABLE_MAGIC {
+            return Err(corrupted("bad magic"));
+        }
+        let version = u16::from_le_bytes([footer[38], footer[39]]);
+        if version != TABLE_VERSION {
+            return Err(corrupted(&format!("unsupported version {}", version)));
+        }
+        let u64_at = |pos: usize| u64::from_le_bytes(footer[pos..pos + 8].try_into().unwrap());
+        let (index_offset, index_len) = (u64_at(0), u64_at(8));
+        let (bloom_offset, bloom_len) = (u64_at(16), u64_at(24));
+        if index_offset + index_len != bloom_offset
+            || bloom_offset + bloom_len + FOOTER_SIZE as u64 != size
+        {
+            return Err(corrupted("bad section offsets"));
+        }
+        let index = read_checked(&file, index_offset, index_len)
+            .ok_or_else(|| corrupted("index checksum mismatch"))?;
+        let (first_key, handles) =
+            parse_index(&index).ok_or_else(|| corrupted("malformed index"))?;
+        let bloom = read_checked(&file, bloom_offset, bloom_len)
+            .and_then(|buf| Bloom::decode(&buf))
+            .ok_or_else(|| corrupted("bad bloom filter"))?;
+        Ok(Table {
+            file_id,
+            path,
+            file,
+            size,
+            first_key,
+            index: handles,
+            bloom,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// The table is no longer in the manifest, a snapshot or a scan may still read it.
+    pub(crate) fn mark_obsolete(&self) {
+        self.obsolete.store(true, AtomicOrdering::SeqCst);
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    pub(crate) fn last_key(&self) -> &[u8] {
+        self.index
+            .last()
+            .map_or(&[][..], |handle| handle.last_key.as_slice())
+    }
+
+    /// Whether the keys of the table may be in `[start, end]`.
+    pub(crate) fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        !self.index.is_empty() && self.first_key() <= end && self.last_key() >= start
+    }
+
+    /// Return the entry of `key`, which may be a tombstone, or None if the table does not have it.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if !self.bloom.may_contain(bloom_hashes(key)) {
+            return Ok(None);
+        }
+        let block_id = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        if block_id == self.index.len() {
+            return Ok(None);
+        }
+        for (entry_key, entry) in self.read_block(block_id)? {
+            match entry_key.as_slice().cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(Some(entry)),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate the entries from the first key not less than `start`.
+    pub(crate) fn iter_from(table: Arc<Table>, start: &[u8]) -> TableIter {
+        let block_id = table
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < start);
+        TableIter {
+            table,
+            block_id,
+            entries: Vec::new().into_iter(),
+            start: Some(start.to_vec()),
+        }
+    }
+
+    fn read_block(&self, block_id: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let handle = &self.index[block_id];
+        let corrupted = |reason: &str| KvsError::CorruptedTable {
+            file_id: self.file_id,
+            reason: format!("block at offset {}: {}", handle.offset, reason),
+            backtrace: Backtrace::force_capture(),
+        };
+        let buf = read_checked(&self.file, handle.offset, handle.size as u64)
+            .ok_or_else(|| corrupted("checksum mismatch"))?;
+        parse_block(&buf).ok_or_else(|| corrupted("malformed entry"))
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(AtomicOrdering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                log::error!("remove obsolete table {:?} failed: {:?}", self.path, e);
+            }
+        }
+    }
+}
+
+/// Entries of a table in key order, one block is read at a time.
+pub(crate) struct TableIter {
+    table: Arc<Table>,
+    block_id: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, Entry)>,
+    // entries before it are skipped in the first block read
+    start: Option<Vec<u8>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.entries.next() {
+                return Some(Ok(pair));
+            }
+            if self.block_id >= self.table.index.len() {
+                return None;
+            }
+            let mut entries = match self.table.read_block(self.block_id) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.block_id = self.table.index.len();
+                    return Some(Err(e));
+                }
+            };
+            self.block_id += 1;
+            if let Some(start) = self.start.take() {
+                entries.retain(|(key, _)| *key >= start);
+            }
+            self.entries = entries.into_iter();
+        }
+    }
+}
+
+// read a section that ends with the crc32 of the rest, and return it without the crc
+fn read_checked(file: &File, offset: u64, len: u64) -> Option<Vec<u8>> {
+    if len < 4 {
+        return None;
+    }
+    let mut buf = vec![0; len as usize];
+    read_exact_at(file, &mut buf, offset).ok()?;
+    let crc = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
+    buf.truncate(buf.len() - 4);
+    if crc32fast::hash(&buf) != crc {
+        return None;
+    }
+    Some(buf)
+}
+
+// a cursor over a buffer, every read returns None past the end
+struct Cursor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+fn parse_index(buf: &[u8]) -> Option<(Vec<u8>, Vec<BlockHandle>)> {
+    let mut cursor = Cursor { buf };
+    let first_key_len = cursor.u32()? as usize;
+    let first_key = cursor.bytes(first_key_len)?.to_vec();
+    let count = cursor.u32()?;
+    let mut handles = Vec::new();
+    for _ in 0..count {
+        let key_len = cursor.u32()? as usize;
+        let offset = cursor.u64()?;
+        let size = cursor.u32()?;
+        let last_key = cursor.bytes(key_len)?.to_vec();
+        handles.push(BlockHandle {
+            last_key,
+            offset,
+            size,
+        });
+    }
+    Some((first_key, handles))
+}
+
+fn parse_block(buf: &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut cursor = Cursor { buf };
+    let mut entries = Vec::new();
+    while !cursor.buf.is_empty() {
+        let key_len = cursor.u32()? as usize;
+        let value_len = cursor.u32()? as usize;
+        let expire_at = cursor.u64()?;
+        let kind = cursor.bytes(1)?[0];
+        let key = cursor.bytes(key_len)?.to_vec();
+        let value = cursor.bytes(value_len)?;
+        let value = match kind {
+            0 => None,
+            1 => Some(value.to_vec()),
+            _ => return None,
+        };
+        entries.push((key, Entry { value, expire_at }));
+    }
+    Some(entries)
+}
+
+// the second hash mixes the first through a multiplicative hash, a crc32 of the key with another
+// initial value would differ from the first by a constant for all keys of a length
+fn bloom_hashes(key: &[u8]) -> (u32, u32) {
+    let h1 = crc32fast::hash(key);
+    let h2 = ((h1.rotate_right(17) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as u32;
+    (h1, h2 | 1)
+}
+
+/// Bloom filter over the keys of a table, probed with double hashing.
+struct Bloom {
+    hash_count: u32,
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    fn new(key_count: u64) -> Bloom {
+        let bit_count = (key_count * BLOOM_BITS_PER_KEY).max(64);
+        Bloom {
+            hash_count: BLOOM_HASH_COUNT,
+            bits: vec![0; bit_count.div_ceil(8) as usize],
+        }
+    }
+
+    fn positions(&self, (h1, h2): (u32, u32)) -> impl Iterator<Item = usize> {
+        let bit_count = self.bits.len() as u64 * 8;
+        (0..self.hash_count as u64).map(move |i| ((h1 as u64 + i * h2 as u64) % bit_count) as usize)
+    }
+
+    fn encode(mut self, key_hashes: &[(u32, u32)]) -> Vec<u8> {
+        for hashes in key_hashes {
+            for pos in self.positions(*hashes).collect::<Vec<_>>() {
+                self.bits[pos / 8] |= 1 << (pos % 8);
+            }
+        }
+        let mut buf = Vec::with_capacity(4 + self.bits.len());
+        buf.extend_from_slice(&self.hash_count.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Bloom> {
+        let mut cursor = Cursor { buf };
+        let hash_count = cursor.u32()?;
+        if cursor.buf.is_empty() {
+            return None;
+        }
+        Some(Bloom {
+            hash_count,
+            bits: cursor.buf.to_vec(),
+        })
+    }
+
+    fn may_contain(&self, hashes: (u32, u32)) -> bool {
+        self.positions(hashes)
+            .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
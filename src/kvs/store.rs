@@ -1,247 +1,1410 @@
 #![deny(missing_docs)]
 //! A simple library for a simple KV in-memory database.
+use super::durability::{Durability, Syncer};
 use super::error::Result;
-use serde::{Deserialize, Serialize};
+use super::hint::{hint_path, read_hint_file, write_hint_file, HintEntry};
+use super::meta::{check_meta, open_meta, MetaGuard};
+use super::mmap::Mmap;
+use super::record::{
+    migrate_binary_segment, migrate_legacy_segment, read_segment_format, write_segment_header,
+    Command, Record, RecordReader, SegmentFormat, SEGMENT_HEADER_SIZE,
+};
+use super::snapshot::{scan_keys, KvsSnapshot};
+use anyhow::anyhow;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_skiplist::SkipMap;
+use fs2::FileExt;
 use std::backtrace::Backtrace;
-use std::collections::{BTreeMap, HashMap};
-use std::env::current_dir;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::ops::Deref;
+use std::io::BufWriter;
+use std::mem;
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 // use std::sync::Arc;
-use std::time;
+use std::thread::{self, JoinHandle};
+use std::time::{self, Duration};
 
 use super::error::KvsError;
 
-/// A simple KV in-memory database. For readability, the commands are serialized by json, and using \n for separation.
+// locked by the process that has the directory open
+const LOCK_FILE: &str = "LOCK";
+// lookups of a key whose log file is missing, before the index is taken as corrupted
+const MISSING_FILE_RETRIES: usize = 100;
+// copies of a scanned range that a write overlaps, before the scan holds the writes back
+const SCAN_ATTEMPTS: usize = 8;
+// extension a compacted log file is renamed to while a snapshot still reads it
+const RETIRED_EXTENSION: &str = "retired";
+// extension of the output of a compaction until it is complete, it is only renamed to N.db then
+const COMPACT_EXTENSION: &str = "compact";
+
+/// A simple KV in-memory database. The commands are appended to log files as checksummed binary records.
 /// ```rust
 /// # use std::error::Error;
-/// # use kvs::KvStore;
+/// # use kvs::{KvStore, KvsEngine, KvsEngineFactory};
 /// # fn main() -> Result<(), Box<dyn Error>> {
-///     let mut db = KvStore::new();
-///     db.set("key1".to_string(), "value1".to_string())?;
-///     assert_eq!("value1", db.get("key1".to_string()).unwrap());
+///     let db = KvStore::new()?;
+///     db.set("key1", "value1")?;
+///     assert_eq!(Some("value1".to_owned()), db.get("key1")?);
 /// #   Ok(())
 /// # }
 /// ```
 pub struct KvStore {
-    db: Arc<RwLock<KvDB>>,
+    // readers only go through the index and the file handles, which are both lock-free,
+    // so a get never waits for a writer or for compaction
+    indexes: Arc<KeyDir>,
+    files: Arc<SkipMap<u64, Arc<File>>>,
+    // maps of the immutable files, the active one is read with positioned reads
+    maps: Arc<SkipMap<u64, Arc<Mmap>>>,
+    db: Arc<Mutex<KvDB>>,
+    // writes waiting for the writer lock, the writer that gets it commits all of them at once
+    pending: Arc<Mutex<Vec<PendingWrite>>>,
+    compactor: Arc<Compactor>,
+    // only for `Durability::Interval`
+    syncer: Option<Arc<Syncer>>,
+    meta: Arc<MetaGuard>,
+    // advisory lock on the LOCK file, released once the compaction thread is done
+    lock: Arc<File>,
+}
+
+/// Options of a KvStore: when the log files roll over, when they are compacted and when they are synced.
+/// ```rust
+/// # use kvs::{Durability, KvStore, KvStoreOptions};
+/// let options = KvStoreOptions::new()
+///     .max_segment_size(1024 * 1024)
+///     .garbage_ratio(0.5)
+///     .garbage_threshold(64 * 1024)
+///     .durability(Durability::EveryN(16));
+/// let db = KvStore::open_with_options("data", options);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    max_segment_size: u64,
+    garbage_ratio: f64,
+    garbage_threshold: u64,
+    read_only: bool,
+    durability: Durability,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            max_segment_size: 1024 * 1024,
+            garbage_ratio: 0.5,
+            // 4 kb, for testing compatibility
+            garbage_threshold: 4 * 1024,
+            read_only: false,
+            durability: Durability::OsBuffered,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Roll over to a new log file once the active one reaches `size` bytes.
+    pub fn max_segment_size(mut self, size: u64) -> Self {
+        self.max_segment_size = size;
+        self
+    }
+
+    /// Compact a log file once this fraction of it is garbage, which are overwritten or removed values.
+    pub fn garbage_ratio(mut self, ratio: f64) -> Self {
+        self.garbage_ratio = ratio;
+        self
+    }
+
+    /// Do not compact anything until the garbage of all log files exceeds `size` bytes.
+    pub fn garbage_threshold(mut self, size: u64) -> Self {
+        self.garbage_threshold = size;
+        self
+    }
+
+    /// Open the store for reads only, sharing the directory with other read-only opens.
+    /// Writes and compaction fail with `KvsError::ReadOnly`, and the files are not modified.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sync the active log file according to `durability`, see `Durability`. Defaults to `OsBuffered`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
 }
 
+// state of the writer, shared with the compaction thread
 struct KvDB {
     active_file_id: u64,
     dir: PathBuf,
-    file_handles: BTreeMap<u64, File>,
-    indexes: HashMap<String, Index>,
-    uncompacted_size: u64,
+    files: Arc<SkipMap<u64, Arc<File>>>,
+    maps: Arc<SkipMap<u64, Arc<Mmap>>>,
+    indexes: Arc<KeyDir>,
+    segments: BTreeMap<u64, SegmentStats>,
+    options: KvStoreOptions,
+    // running compaction, at most one at a time
+    compaction: Option<CompactionTask>,
+    // appends to the active file since it was last synced
+    unsynced_writes: u64,
+    // buffered appender of the active file, at the end of it, None when read-only
+    writer: Option<BufWriter<File>>,
+    // open snapshots reading each log file
+    pins: HashMap<u64, usize>,
+    // compacted files left as N.retired for the snapshots pinning them
+    retired: HashSet<u64>,
 }
 
-use super::engine::KvsEngine;
+use super::engine::{BatchOp, BytesScanIter, KvsEngine, KvsEngineFactory, WriteBatch};
+
+impl KvsEngineFactory for KvStore {
+    /// Open the KvStore at a given path. Return the KvStore.
+    fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+}
 
 impl KvsEngine for KvStore {
-    /// Open the KvStore at current path. Return the KvStore.
-    fn new() -> Result<KvStore> {
-        KvStore::open(current_dir()?)
+    /// Set the value of a key. Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let record = Record {
+            command: Command::Set,
+            tstamp: now_micros()?,
+            expire_at: 0,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.insert_record(record)
+    }
+    /// Set the value of a string key that expires after `ttl`. The expiry is written in the record.
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let tstamp = now_micros()?;
+        let record = Record {
+            command: Command::Set,
+            tstamp,
+            expire_at: tstamp.saturating_add(ttl.as_micros() as u64),
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.insert_record(record)
+    }
+    /// Return the time left before a key expires, from the index without reading the log.
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        let now = now_micros()?;
+        match self.indexes.get(key) {
+            Some(index) if !index.is_expired(now) => Ok(match index.expire_at {
+                0 => None,
+                expire_at => Some(Duration::from_micros(expire_at - now)),
+            }),
+            _ => Err(KvsError::KeyNotFound {
+                key: String::from_utf8_lossy(key).to_string(),
+                backtrace: Backtrace::force_capture(),
+            }),
+        }
+    }
+    /// Get the value of a key. If the key does not exist, return None. Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_micros()?;
+        let mut retries = 0;
+        loop {
+            let index = match self.indexes.get(key) {
+                Some(index) if !index.is_expired(now) => index,
+                _ => return Ok(None),
+            };
+            let map = self
+                .maps
+                .get(&index.file_id)
+                .map(|entry| entry.value().clone());
+            let mut buf = Vec::new();
+            let bytes = match &map {
+                // immutable file, the record is decoded straight from the mapping
+                Some(map) => mapped_record(map, &index)?,
+                None => {
+                    // compaction removes a file only after the index has moved away from it, so a missing
+                    // file means that the index we got is outdated and the next lookup finds the new location
+                    let file = match self.files.get(&index.file_id) {
+                        Some(entry) => entry.value().clone(),
+                        None if retries < MISSING_FILE_RETRIES => {
+                            retries += 1;
+                            continue;
+                        }
+                        None => {
+                            return Err(KvsError::Corrupted {
+                                file_id: index.file_id,
+                                offset: index.value_pos,
+                                reason: "the index points to a missing log file".to_string(),
+                                backtrace: Backtrace::force_capture(),
+                            })
+                        }
+                    };
+                    // positioned read, there is no file cursor shared between readers
+                    buf.resize(index.value_sz as usize, 0);
+                    read_exact_at(&file, &mut buf, index.value_pos)?;
+                    &buf
+                }
+            };
+            return decode_value(&index, bytes).map(Some);
+        }
+    }
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let record = Record {
+            command: Command::Remove,
+            tstamp: now_micros()?,
+            expire_at: 0,
+            key: key.to_vec(),
+            value: Vec::new(),
+        };
+        self.insert_record(record)
     }
 
-    /// Open the KvStore at a given path. Return the KvStore.
-    fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+    /// Write the batch between a begin and a commit record, with a single write.
+    /// The index is updated for all of the operations at once, readers see all of them or none.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.commit(PendingOp::Batch(batch, now_micros()?))
+    }
+
+    /// Readers do not take the writer lock, but no other write can happen between the check and the swap.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut db = self.db.lock().unwrap();
+        let current = self.get_bytes(key)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        let record = match new {
+            Some(value) => Record {
+                command: Command::Set,
+                tstamp: now_micros()?,
+                expire_at: 0,
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None if current.is_some() => Record {
+                command: Command::Remove,
+                tstamp: now_micros()?,
+                expire_at: 0,
+                key: key.to_vec(),
+                value: Vec::new(),
+            },
+            // absent and expected to stay absent
+            None => return Ok(true),
+        };
+        self.append_records(&mut db, vec![record], 1)?;
+        Ok(true)
+    }
+
+    /// Scan the keys in order. The keys of the range are taken from the index at once, with the files
+    /// they point into, so that the scan sees a write batch whole or not at all. The values are read lazily.
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        if matches!(end, Some(end) if end <= start) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let now = now_micros()?;
+        let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let collect = |map: &SkipMap<Vec<u8>, Index>| {
+            let entries: Vec<(Vec<u8>, Index)> = map
+                .range::<[u8], _>((Bound::Included(start), upper))
+                .filter(|entry| !entry.value().is_expired(now))
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect();
+            // compaction removes a file within the change of the index that moves its keys away
+            let file_ids: HashSet<u64> = entries.iter().map(|(_, index)| index.file_id).collect();
+            let segments = Segments::new(&self.files, &self.maps, file_ids);
+            (entries, segments)
+        };
+        let (entries, segments) = match self.indexes.try_read(SCAN_ATTEMPTS, collect) {
+            Some(scanned) => scanned,
+            None => {
+                // a long range that the writes keep overlapping, hold them back meanwhile
+                let _db = self.db.lock().unwrap();
+                self.indexes.read(collect)
+            }
+        };
+        Ok(Box::new(entries.into_iter().map(move |(key, index)| {
+            Ok((key, segments.read_value(&index)?))
+        })))
+    }
+
+    /// The live part of the index is copied under the writer lock, which holds the writes back meanwhile,
+    /// and the values are read from the log files later on. Every file is pinned until the snapshot
+    /// is dropped, compaction leaves the ones it merges in place for it.
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        let mut db = self.db.lock().unwrap();
+        let now = now_micros()?;
+        let indexes: BTreeMap<Vec<u8>, Index> = self
+            .indexes
+            .iter()
+            .filter(|(_, index)| !index.is_expired(now))
+            .collect();
+        let file_ids: Vec<u64> = self.files.iter().map(|entry| *entry.key()).collect();
+        for file_id in &file_ids {
+            *db.pins.entry(*file_id).or_default() += 1;
+        }
+        Ok(Box::new(KvStoreSnapshot {
+            state: Arc::new(SnapshotState {
+                indexes,
+                segments: Segments::new(&self.files, &self.maps, file_ids),
+                db: self.db.clone(),
+                _lock: self.lock.clone(),
+            }),
+        }))
+    }
+}
+
+/// Snapshot of a KvStore: a copy of the index, and the files it points into.
+struct KvStoreSnapshot {
+    state: Arc<SnapshotState>,
+}
+
+struct SnapshotState {
+    indexes: BTreeMap<Vec<u8>, Index>,
+    segments: Segments,
+    // to unpin the files once dropped
+    db: Arc<Mutex<KvDB>>,
+    // the directory stays locked while the snapshot reads it
+    _lock: Arc<File>,
+}
+
+impl Drop for SnapshotState {
+    fn drop(&mut self) {
+        let mut db = self.db.lock().unwrap();
+        for file_id in self.segments.files.keys() {
+            db.unpin(*file_id);
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.state.indexes.get(key) {
+            Some(index) => self.state.segments.read_value(index).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        let state = self.state.clone();
+        let indexes = state.clone();
+        let keys = scan_keys(start, end, limit).over(move |bound| {
+            indexes
+                .indexes
+                .range::<Vec<u8>, _>((bound, Bound::Unbounded))
+                .next()
+                .map(|(key, index)| (key.clone(), index.clone()))
+        });
+        Ok(Box::new(keys.map(move |(key, index)| {
+            Ok((key, state.segments.read_value(&index)?))
+        })))
+    }
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            indexes: self.indexes.clone(),
+            files: self.files.clone(),
+            maps: self.maps.clone(),
+            db: self.db.clone(),
+            pending: self.pending.clone(),
+            compactor: self.compactor.clone(),
+            syncer: self.syncer.clone(),
+            meta: self.meta.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Index {
+    file_id: u64,
+    value_sz: u64,
+    value_pos: u64,
+    // copied from the record, 0 if the key never expires
+    expire_at: u64,
+}
+
+impl Index {
+    fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expire_at, now)
+    }
+}
+
+/// Lock-free index of the live records, changed only by the writer.
+/// The writer makes its changes within `update`, which keeps `version` odd meanwhile, and readers
+/// retry until they read the index at an even version that did not change, so that they see
+/// all of a group of changes, such as a write batch, or none of them.
+struct KeyDir {
+    map: SkipMap<Vec<u8>, Index>,
+    // odd while the writer changes the index
+    version: AtomicU64,
+}
+
+impl KeyDir {
+    fn new() -> KeyDir {
+        KeyDir {
+            map: SkipMap::new(),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Index> {
+        self.read(|map| map.get(key).map(|entry| entry.value().clone()))
+    }
+
+    /// Run `read` against a state of the index that no change of the writer is partly applied to.
+    fn read<T>(&self, read: impl FnMut(&SkipMap<Vec<u8>, Index>) -> T) -> T {
+        self.try_read(usize::MAX, read).unwrap()
+    }
+
+    /// Like `read`, but give up and return None after `attempts` runs of `read` that a change overlapped.
+    fn try_read<T>(
+        &self,
+        attempts: usize,
+        mut read: impl FnMut(&SkipMap<Vec<u8>, Index>) -> T,
+    ) -> Option<T> {
+        let mut failed = 0;
+        while failed < attempts {
+            let version = self.version.load(Ordering::SeqCst);
+            if version & 1 == 0 {
+                let value = read(&self.map);
+                if self.version.load(Ordering::SeqCst) == version {
+                    return Some(value);
+                }
+                failed += 1;
+            }
+            std::hint::spin_loop();
+        }
+        None
+    }
+
+    /// Make the changes of `write` visible to the readers all at once.
+    /// Only called with the writer lock held, the writer looks keys up with `get_current` meanwhile.
+    fn update<T>(&self, write: impl FnOnce() -> T) -> T {
+        // even again if `write` panics, or the readers would wait forever
+        struct Done<'a>(&'a AtomicU64);
+        impl Drop for Done<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.version.fetch_add(1, Ordering::SeqCst);
+        let _done = Done(&self.version);
+        write()
+    }
+
+    /// The entry of `key` as the writer left it, without waiting for a stable version.
+    fn get_current(&self, key: &[u8]) -> Option<Index> {
+        self.map.get(key).map(|entry| entry.value().clone())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Like `contains_key`, but an expired key does not count.
+    fn contains_live_key(&self, key: &[u8], now: u64) -> bool {
+        matches!(self.get(key), Some(index) if !index.is_expired(now))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Index)> + '_ {
+        self.map
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+    }
+
+    // only called by the writer, within `update` once readers may be running
+    fn insert(&self, key: Vec<u8>, index: Index) -> Option<Index> {
+        let old_index = self.get_current(&key);
+        self.map.insert(key, index);
+        old_index
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<Index> {
+        self.map.remove(key).map(|entry| entry.value().clone())
+    }
+}
+
+/// Handles of the log files that some index entries point into, so that their values can be read
+/// later on, even once compaction has removed the files from the store.
+struct Segments {
+    files: HashMap<u64, Arc<File>>,
+    maps: HashMap<u64, Arc<Mmap>>,
+}
+
+impl Segments {
+    fn new(
+        files: &SkipMap<u64, Arc<File>>,
+        maps: &SkipMap<u64, Arc<Mmap>>,
+        file_ids: impl IntoIterator<Item = u64>,
+    ) -> Segments {
+        let mut segments = Segments {
+            files: HashMap::new(),
+            maps: HashMap::new(),
+        };
+        for file_id in file_ids {
+            if let Some(entry) = files.get(&file_id) {
+                segments.files.insert(file_id, entry.value().clone());
+            }
+            if let Some(entry) = maps.get(&file_id) {
+                segments.maps.insert(file_id, entry.value().clone());
+            }
+        }
+        segments
+    }
+
+    fn read_value(&self, index: &Index) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let bytes = match self.maps.get(&index.file_id) {
+            Some(map) => mapped_record(map, index)?,
+            None => {
+                let file = self
+                    .files
+                    .get(&index.file_id)
+                    .ok_or_else(|| KvsError::Corrupted {
+                        file_id: index.file_id,
+                        offset: index.value_pos,
+                        reason: "the index points to a missing log file".to_string(),
+                        backtrace: Backtrace::force_capture(),
+                    })?;
+                buf.resize(index.value_sz as usize, 0);
+                read_exact_at(file, &mut buf, index.value_pos)?;
+                &buf
+            }
+        };
+        decode_value(index, bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SegmentStats {
+    // bytes in the file, segment header included
+    size: u64,
+    // bytes of records that are overwritten or removed, and of tombstones that can be dropped
+    garbage: u64,
+}
+
+impl SegmentStats {
+    fn new_file() -> SegmentStats {
+        SegmentStats {
+            size: SEGMENT_HEADER_SIZE,
+            garbage: 0,
+        }
+    }
+
+    fn garbage_ratio(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        self.garbage as f64 / self.size as f64
+    }
+}
+
+// batch read using BufReader is necessary because although the OS reads ~4kb block from disk into page cache every time
+// but read is a system call which is realtively expensive
+// need to perform reads from the log at arbitrary offsets. Consider how that might impact the way you manage file handles.
+// compact the log file
+impl KvStore {
+    /// Open the KvStore at a given path with the given options. Return the KvStore.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let dir = path.into();
         fs::create_dir_all(&dir)?;
+        let read_only = options.read_only;
+        let durability = options.durability;
+        let lock = Arc::new(lock_dir(&dir, read_only)?);
+        let meta = Arc::new(match read_only {
+            true => check_meta(&dir, "kvs")?,
+            false => open_meta(&dir, "kvs")?,
+        });
         let db_file_ids = get_db_files_ids(&dir)?;
-        let mut file_handles = get_file_handles(&dir, &db_file_ids)?;
+        if read_only {
+            check_segments(&dir, &db_file_ids)?;
+        } else {
+            upgrade_segments(&dir, &db_file_ids)?;
+            // left by snapshots that were still open when the store was last closed
+            remove_retired_files(&dir)?;
+            // left by a compaction that was interrupted by a crash, the files it merges are all still there
+            remove_partial_compactions(&dir)?;
+        }
+        let mut file_handles = get_file_handles(&dir, &db_file_ids, read_only)?;
         // build index
-        let (indexes, uncompacted_size) = build_indexes(&mut file_handles)?;
+        let (indexes, mut segments) = build_indexes(&dir, &mut file_handles, read_only)?;
         let active_file_id: u64;
-        if db_file_ids.is_empty() {
+        if read_only {
+            // there is no file to append to, 0 if the directory is empty
+            active_file_id = db_file_ids.last().cloned().unwrap_or_default();
+        } else if db_file_ids.is_empty() {
             // create new file
-            let file_handle = generate_new_file(&dir, 1)?;
+            let file_handle = create_log_file(&dir, 1)?;
             file_handles.insert(1, file_handle);
+            segments.insert(1, SegmentStats::new_file());
             active_file_id = 1;
         } else {
             active_file_id = *db_file_ids.last().unwrap();
         }
+        let maps = SkipMap::new();
+        for (file_id, file) in &file_handles {
+            // nothing is appended to the files of a read-only store, they are all mapped
+            if *file_id != active_file_id || read_only {
+                map_segment(&maps, *file_id, file)?;
+            }
+        }
+        let maps = Arc::new(maps);
+        let files: Arc<SkipMap<u64, Arc<File>>> = Arc::new(
+            file_handles
+                .into_iter()
+                .map(|(file_id, file)| (file_id, Arc::new(file)))
+                .collect(),
+        );
+        let indexes = Arc::new(indexes);
+        let writer = match files.get(&active_file_id) {
+            Some(entry) if !read_only => {
+                let mut writer = BufWriter::new(entry.value().try_clone()?);
+                writer.seek(std::io::SeekFrom::Start(segments[&active_file_id].size))?;
+                Some(writer)
+            }
+            _ => None,
+        };
         let kv_db = KvDB {
             active_file_id,
             dir,
-            file_handles,
-            indexes,
-            uncompacted_size,
+            files: files.clone(),
+            maps: maps.clone(),
+            indexes: indexes.clone(),
+            segments,
+            options,
+            compaction: None,
+            unsynced_writes: 0,
+            writer,
+            pins: HashMap::new(),
+            retired: HashSet::new(),
+        };
+        let db = Arc::new(Mutex::new(kv_db));
+        let compactor = Arc::new(Compactor::spawn(db.clone())?);
+        let syncer = match durability.interval() {
+            Some(interval) if !read_only => {
+                let db = db.clone();
+                let syncer = Syncer::spawn("kvs-sync", interval, move || sync_in_background(&db))?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
         };
         Ok(KvStore {
-            db: Arc::new(RwLock::new(kv_db)),
+            indexes,
+            files,
+            maps,
+            db,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            compactor,
+            syncer,
+            meta,
+            lock,
         })
     }
 
-    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let record = Record {
-            command: Command::Set,
-            tstamp: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)?
-                .as_micros(),
-            key,
-            value,
+    /// Open the KvStore at a given path for reads only, see `KvStoreOptions::read_only`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// Compact every log file into one now, after waiting for a running background compaction.
+    pub fn compact(&self) -> Result<()> {
+        check_writable(&self.db.lock().unwrap())?;
+        let task = loop {
+            {
+                let mut db = self.db.lock().unwrap();
+                if db.compaction.is_none() {
+                    let file_ids = db.segments.keys().cloned().collect();
+                    break db.start_compaction(file_ids)?;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
         };
-        self.clone().insert_record(record)?;
+        run_compaction_task(&self.db, task)
+    }
+
+    fn insert_record(&self, record: Record) -> Result<()> {
+        self.commit(PendingOp::Record(record))
+    }
+
+    /// Queue the write and wait for it to be committed, either by this writer or by the one
+    /// holding the writer lock meanwhile, which commits every queued write with a single append.
+    fn commit(&self, op: PendingOp) -> Result<()> {
+        let (sender, receiver) = bounded(1);
+        self.pending
+            .lock()
+            .unwrap()
+            .push(PendingWrite { op, result: sender });
+        let mut db = self.db.lock().unwrap();
+        // a leader answers every write of its group before it releases the lock
+        if let Ok(result) = receiver.try_recv() {
+            return result;
+        }
+        let group = mem::take(&mut *self.pending.lock().unwrap());
+        self.commit_group(&mut db, group);
+        drop(db);
+        receiver
+            .recv()
+            .map_err(|_| anyhow!("write dropped by the group commit"))?
+    }
+
+    /// Check the writes of the group in order and append the records of those that pass together,
+    /// then send each writer its result.
+    fn commit_group(&self, db: &mut KvDB, group: Vec<PendingWrite>) {
+        if db.options.read_only {
+            for write in group {
+                let _ = write.result.send(check_writable(db));
+            }
+            return;
+        }
+        let mut records = Vec::new();
+        let mut committed = Vec::with_capacity(group.len());
+        // keys set or removed earlier in the group, shadowing the index
+        let mut live_keys: HashMap<Vec<u8>, bool> = HashMap::new();
+        for write in group {
+            match write.op.into_records(&db.indexes, &mut live_keys) {
+                Ok(write_records) => {
+                    records.extend(write_records);
+                    committed.push(write.result);
+                }
+                Err(e) => {
+                    let _ = write.result.send(Err(e));
+                }
+            }
+        }
+        if committed.is_empty() {
+            return;
+        }
+        let writes = committed.len() as u64;
+        match self.append_records(db, records, writes) {
+            Ok(()) => {
+                for result in committed {
+                    let _ = result.send(Ok(()));
+                }
+            }
+            Err(e) => {
+                let reason = format!("{:?}", e);
+                let mut committed = committed.into_iter();
+                if let Some(first) = committed.next() {
+                    let _ = first.send(Err(e));
+                }
+                for result in committed {
+                    let _ = result.send(Err(anyhow!("group commit failed: {}", reason).into()));
+                }
+            }
+        }
+    }
+
+    /// Append the records of `writes` writes to the active file with a single write,
+    /// sync it if the durability calls for it, then apply them to the index.
+    fn append_records(&self, db: &mut KvDB, records: Vec<Record>, writes: u64) -> Result<()> {
+        check_writable(db)?;
+        if db.segments[&db.active_file_id].size >= db.options.max_segment_size {
+            let file_id = db.active_file_id + 1;
+            db.roll_active_file(file_id)?;
+        }
+        let active_file_id = db.active_file_id;
+        let start = db.segments[&active_file_id].size;
+        // synced before the index shows the records to readers
+        db.unsynced_writes += writes;
+        let written = db.write_active_file(&records).and_then(|sizes| {
+            if db.options.durability.needs_sync(db.unsynced_writes) {
+                db.sync_active_file()?;
+            }
+            Ok(sizes)
+        });
+        let sizes = match written {
+            Ok(sizes) => sizes,
+            Err(e) => {
+                // nothing of a failed append is indexed, it must not be left in the file either
+                db.reset_writer(start)?;
+                return Err(e);
+            }
+        };
+        let mut pos = start;
+        let now = now_micros()?;
+        // all the records of a group commit or a batch show up to readers together
+        let indexes = db.indexes.clone();
+        indexes.update(|| {
+            for (record, size) in records.into_iter().zip(sizes) {
+                apply_record(
+                    &indexes,
+                    &mut db.segments,
+                    active_file_id,
+                    pos,
+                    size,
+                    record,
+                    now,
+                );
+                pos += size;
+            }
+        });
+        db.segments.get_mut(&active_file_id).unwrap().size = pos;
+
+        if db.compaction.is_none() {
+            if let Some(file_ids) = db.pick_compaction() {
+                let task = db.start_compaction(file_ids)?;
+                self.compactor.send(task)?;
+            }
+        }
         Ok(())
     }
-    /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let db = self.db.read().unwrap();
-        let index = db.indexes.get(&key);
-        match index {
-            Some(index) => {
-                let file = db.file_handles.get(&index.file_id).unwrap();
-                // maybe because Seek trait have implemented for &File so this can work
-                #[allow(clippy::clone_double_ref)]
-                file.deref()
-                    .clone()
-                    .seek(std::io::SeekFrom::Start(index.value_pos))?;
-                // with out take serde_json don't know how long to read
-                let cmd_reader = file.deref().take(index.value_sz as u64);
-                let record: Record = serde_json::from_reader(cmd_reader)?;
-                Ok(Some(record.value))
+}
+
+impl KvDB {
+    fn roll_active_file(&mut self, file_id: u64) -> Result<()> {
+        // the background sync only sees the active file
+        if self.options.durability != Durability::OsBuffered {
+            self.sync_active_file()?;
+        }
+        let file = create_log_file(&self.dir, file_id)?;
+        let writer = BufWriter::new(file.try_clone()?);
+        let previous_file_id = mem::replace(&mut self.active_file_id, file_id);
+        self.writer = Some(writer);
+        self.files.insert(file_id, Arc::new(file));
+        self.segments.insert(file_id, SegmentStats::new_file());
+        // nothing is appended to the previous active file anymore, without a map it is still read
+        // with positioned reads, so a failed map does not fail the write that rolled over
+        if let Some(entry) = self.files.get(&previous_file_id) {
+            if let Err(e) = map_segment(&self.maps, previous_file_id, entry.value()) {
+                log::error!("mapping {}.db failed: {:?}", previous_file_id, e);
             }
-            None => Ok(None),
         }
+        Ok(())
     }
-    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        let record = Record {
-            command: Command::Remove,
-            tstamp: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)?
-                .as_micros(),
-            key,
-            value: "".to_string(),
+
+    /// Write the encoded records at the end of the active file, return their sizes.
+    /// The buffer is flushed before returning, readers find the records in the file once indexed.
+    fn write_active_file(&mut self, records: &[Record]) -> Result<Vec<u64>> {
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            // a failed reset left no writer, the file is cut back to what is indexed before appending
+            None => self.open_writer(self.segments[&self.active_file_id].size)?,
         };
-        self.clone().insert_record(record)?;
+        let writer = self.writer.insert(writer);
+        let mut sizes = Vec::with_capacity(records.len());
+        for record in records {
+            let encoded = record.encode();
+            sizes.push(encoded.len() as u64);
+            writer.write_all(&encoded)?;
+        }
+        writer.flush()?;
+        Ok(sizes)
+    }
+
+    /// Cut the active file back to `len` after a failed append, dropping what is left in the buffer.
+    /// If that fails too, no writer is left and the next append tries again.
+    fn reset_writer(&mut self, len: u64) -> Result<()> {
+        // dropping the writer would flush what is left in its buffer
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.into_parts();
+        }
+        self.writer = Some(self.open_writer(len)?);
         Ok(())
     }
 
-    fn clone(&self) -> Self {
-        KvStore {
-            db: self.db.clone(),
+    /// Cut the active file to `len` and return an appender at its end.
+    fn open_writer(&self, len: u64) -> Result<BufWriter<File>> {
+        let file = self
+            .files
+            .get(&self.active_file_id)
+            .unwrap()
+            .value()
+            .clone();
+        file.set_len(len)?;
+        let mut writer = BufWriter::new(file.try_clone()?);
+        writer.seek(std::io::SeekFrom::Start(len))?;
+        Ok(writer)
+    }
+
+    /// Sync the appends to the active file since the last sync.
+    fn sync_active_file(&mut self) -> Result<()> {
+        if self.unsynced_writes > 0 {
+            self.files
+                .get(&self.active_file_id)
+                .unwrap()
+                .value()
+                .sync_data()?;
+            self.unsynced_writes = 0;
+        }
+        Ok(())
+    }
+
+    /// Return the log files worth compacting, if there is enough garbage in total.
+    fn pick_compaction(&self) -> Option<Vec<u64>> {
+        let garbage: u64 = self.segments.values().map(|stats| stats.garbage).sum();
+        if garbage <= self.options.garbage_threshold {
+            return None;
+        }
+        let file_ids: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(_, stats)| stats.garbage_ratio() >= self.options.garbage_ratio)
+            .map(|(file_id, _)| *file_id)
+            .collect();
+        if file_ids.is_empty() {
+            return None;
+        }
+        Some(file_ids)
+    }
+
+    fn start_compaction(&mut self, file_ids: Vec<u64>) -> Result<CompactionTask> {
+        // freeze every file written so far, new writes go to a fresh active file while they are merged,
+        // the merged file takes the id in between so that it is replayed after all the files it replaces
+        let task = CompactionTask {
+            compact_file_id: self.active_file_id + 1,
+            file_ids,
+        };
+        self.roll_active_file(self.active_file_id + 2)?;
+        self.compaction = Some(task.clone());
+        Ok(task)
+    }
+
+    /// Release a snapshot's pin of `file_id`, the last one removes the file if compaction retired it.
+    fn unpin(&mut self, file_id: u64) {
+        match self.pins.get_mut(&file_id) {
+            Some(pins) if *pins > 1 => *pins -= 1,
+            _ => {
+                self.pins.remove(&file_id);
+                if self.retired.remove(&file_id) {
+                    if let Err(e) = fs::remove_file(retired_path(&self.dir, file_id)) {
+                        log::error!("remove retired file {}.db failed: {:?}", file_id, e);
+                    }
+                }
+            }
         }
     }
+
+    /// Keep a compacted file that a snapshot still reads, under a name that is not replayed on open.
+    fn retire(&mut self, file_id: u64) -> Result<()> {
+        fs::rename(
+            self.dir.join(format!("{}.db", file_id)),
+            retired_path(&self.dir, file_id),
+        )?;
+        self.retired.insert(file_id);
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone)]
-struct Index {
-    file_id: u64,
-    value_sz: u64,
-    value_pos: u64,
+impl Drop for KvDB {
+    fn drop(&mut self) {
+        // nothing acknowledged is left unsynced by a clean shutdown, unless the OS is trusted with it
+        if self.options.durability != Durability::OsBuffered {
+            if let Err(e) = self.sync_active_file() {
+                log::error!("syncing {}.db failed: {:?}", self.active_file_id, e);
+            }
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
-enum Command {
-    Set,
-    Get,
-    Remove,
+// sync for `Durability::Interval`, writers go on while the file is synced
+fn sync_in_background(db: &Mutex<KvDB>) -> Result<()> {
+    let file = {
+        let mut db = db.lock().unwrap();
+        if db.unsynced_writes == 0 {
+            return Ok(());
+        }
+        db.unsynced_writes = 0;
+        let file = db.files.get(&db.active_file_id).unwrap().value().clone();
+        file
+    };
+    file.sync_data()?;
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Record {
-    command: Command,
-    tstamp: u128,
-    key: String,
-    value: String,
+/// A write queued for the group commit, with the channel its result is sent to.
+struct PendingWrite {
+    op: PendingOp,
+    result: Sender<Result<()>>,
 }
 
-// 4 kb, for testing compatibility
-// const MAX_FILE_SIZE: u64 = 4 * 1024;
-const TRIGGER_COMPACT_SIZE: u64 = 4 * 1024;
+enum PendingOp {
+    Record(Record),
+    // with the timestamp of its records
+    Batch(WriteBatch, u64),
+}
 
-// TO-DO: Buffer and batch write
-// batch read using BufReader is necessary because although the OS reads ~4kb block from disk into page cache every time
-// but read is a system call which is realtively expensive
-// need to perform reads from the log at arbitrary offsets. Consider how that might impact the way you manage file handles.
-// compact the log file
-impl KvStore {
-    fn insert_record(&mut self, record: Record) -> Result<()> {
-        let mut db = self.db.write().unwrap();
-        let mut active_file = get_last_file(&db.file_handles, db.active_file_id)?;
-        let old_pos = active_file.seek(std::io::SeekFrom::End(0))?;
-        serde_json::to_writer(active_file, &record)?;
-        let new_pos = active_file.seek(std::io::SeekFrom::End(0))?;
-        let active_file_id = db.active_file_id;
-        match record.command {
-            Command::Set => {
-                if let Some(old_index) = db.indexes.insert(
-                    record.key,
-                    Index {
-                        file_id: active_file_id,
-                        value_sz: new_pos - old_pos,
-                        value_pos: old_pos,
-                    },
-                ) {
-                    db.uncompacted_size += old_index.value_sz;
-                }
-            }
-            Command::Remove => {
-                if let Some(old_index) = db.indexes.remove(&record.key) {
-                    db.uncompacted_size += old_index.value_sz;
-                } else {
+impl PendingOp {
+    /// Return the records of the write, checked against the index and the keys written before it in its group.
+    fn into_records(
+        self,
+        indexes: &KeyDir,
+        live_keys: &mut HashMap<Vec<u8>, bool>,
+    ) -> Result<Vec<Record>> {
+        let is_live =
+            |live_keys: &HashMap<Vec<u8>, bool>, key: &Vec<u8>, tstamp| match live_keys.get(key) {
+                Some(live) => *live,
+                None => indexes.contains_live_key(key, tstamp),
+            };
+        match self {
+            PendingOp::Record(record) => {
+                if record.command == Command::Remove
+                    && !is_live(live_keys, &record.key, record.tstamp)
+                {
                     return Err(KvsError::KeyNotFound {
-                        key: record.key,
+                        key: String::from_utf8_lossy(&record.key).to_string(),
                         backtrace: Backtrace::force_capture(),
                     });
                 }
+                let live =
+                    record.command == Command::Set && !is_expired(record.expire_at, record.tstamp);
+                live_keys.insert(record.key.clone(), live);
+                Ok(vec![record])
+            }
+            PendingOp::Batch(batch, tstamp) => {
+                let marker = |command| Record {
+                    command,
+                    tstamp,
+                    expire_at: 0,
+                    key: Vec::new(),
+                    value: Vec::new(),
+                };
+                let mut records = vec![marker(Command::BatchBegin)];
+                for op in batch.into_ops() {
+                    let (command, key, value) = match op {
+                        BatchOp::Set(key, value) => (Command::Set, key, value),
+                        // a remove is skipped if the key does not exist then
+                        BatchOp::Remove(key) if !is_live(live_keys, &key, tstamp) => continue,
+                        BatchOp::Remove(key) => (Command::Remove, key, Vec::new()),
+                    };
+                    live_keys.insert(key.clone(), command == Command::Set);
+                    records.push(Record {
+                        command,
+                        tstamp,
+                        expire_at: 0,
+                        key,
+                        value,
+                    });
+                }
+                records.push(marker(Command::BatchCommit));
+                Ok(records)
             }
-            _ => {}
         }
+    }
+}
 
-        if db.uncompacted_size > TRIGGER_COMPACT_SIZE {
-            self.compact(&mut db)?;
-        }
+#[derive(Debug, Clone)]
+struct CompactionTask {
+    compact_file_id: u64,
+    // files to merge, all of them are immutable
+    file_ids: Vec<u64>,
+}
+
+/// Owns the background compaction thread, dropping the last clone of a KvStore waits for it
+/// so that the directory can be reopened safely.
+struct Compactor {
+    sender: Option<Sender<CompactionTask>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    fn spawn(db: Arc<Mutex<KvDB>>) -> Result<Compactor> {
+        let (sender, receiver) = bounded(1);
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_string())
+            .spawn(move || run_compaction(db, receiver))?;
+        Ok(Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    fn send(&self, task: CompactionTask) -> Result<()> {
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(task)
+            .map_err(|_| anyhow!("compaction thread stopped"))?;
         Ok(())
     }
+}
 
-    fn compact(&self, db: &mut KvDB) -> Result<()> {
-        // should not try to get lock here cause function insert_record has get the lock and it will be block forever
-        // let mut db = self.db.lock().unwrap();
-        let mut compact_file = generate_new_file(&db.dir, db.active_file_id + 1)?;
-        // compact all include current active file
-        // use index to find the record
-        let mut active_file_id = db.active_file_id;
-        // when need to change two or more fields in the same time(such as loop), replace/take/swap or RefCell
-        let mut file_handles = std::mem::take(&mut db.file_handles);
-        let indexes = db.indexes.iter_mut();
-        let mut pos = 0;
-        for (_, index) in indexes {
-            let file = file_handles.get_mut(&index.file_id).unwrap();
-            file.seek(std::io::SeekFrom::Start(index.value_pos))?;
-            // tricky, use io::copy to copy the record
-            let mut entry_reader = file.take(index.value_sz);
-            io::copy(&mut entry_reader, &mut compact_file)?;
-            // update index
-            index.file_id = active_file_id + 1;
-            index.value_pos = pos;
-            pos = compact_file.seek(std::io::SeekFrom::End(0))?;
-        }
-        std::mem::swap(&mut db.file_handles, &mut file_handles);
-        // remove old file, can not remove during loop
-        let file_ids: Vec<u64> = db.file_handles.keys().cloned().collect();
-        for file_id in file_ids {
-            db.file_handles.remove(&file_id);
-            fs::remove_file(db.dir.join(format!("{}.db", file_id)))?;
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // the thread finishes the pending task and exits once the channel is closed
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
-        // update file handle and active file id, should be lock if compact by another thread?
-        active_file_id += 1;
-        db.file_handles.insert(active_file_id, compact_file);
-        active_file_id += 1;
-        let active_file = generate_new_file(&db.dir, active_file_id)?;
-        db.file_handles.insert(active_file_id, active_file);
-        db.active_file_id = active_file_id;
+    }
+}
 
-        Ok(())
+fn run_compaction(db: Arc<Mutex<KvDB>>, receiver: Receiver<CompactionTask>) {
+    while let Ok(task) = receiver.recv() {
+        let compact_file_id = task.compact_file_id;
+        if let Err(e) = run_compaction_task(&db, task) {
+            log::error!("compaction into {}.db failed: {:?}", compact_file_id, e);
+        }
+    }
+}
+
+fn run_compaction_task(db: &Mutex<KvDB>, task: CompactionTask) -> Result<()> {
+    let compact_file_id = task.compact_file_id;
+    let result = compact(db, task);
+    if result.is_err() {
+        // the old files are still in use, a partial merged file must not be replayed on next open
+        let mut db = db.lock().unwrap();
+        let _ = fs::remove_file(compact_path(&db.dir, compact_file_id));
+        let _ = fs::remove_file(db.dir.join(format!("{}.db", compact_file_id)));
+        let _ = remove_hint_file(&db.dir, compact_file_id);
+        db.compaction = None;
     }
+    result
+}
+
+/// Merge the live records of `task.file_ids` into `task.compact_file_id`.
+/// Only the final swap holds the writer lock, the copy runs without it.
+fn compact(db: &Mutex<KvDB>, task: CompactionTask) -> Result<()> {
+    let (dir, indexes, oldest_kept_file_id) = {
+        let db = db.lock().unwrap();
+        let oldest_kept_file_id = db
+            .segments
+            .keys()
+            .find(|file_id| !task.file_ids.contains(file_id))
+            .cloned();
+        (db.dir.clone(), db.indexes.clone(), oldest_kept_file_id)
+    };
+    // the old files are immutable, a key that points into them while we iterate is either copied here
+    // or overwritten concurrently, which the swap below detects
+    let now = now_micros()?;
+    let (mut live_entries, expired_entries): (Vec<_>, Vec<_>) = indexes
+        .iter()
+        .filter(|(_, index)| task.file_ids.contains(&index.file_id))
+        .partition(|(_, index)| !index.is_expired(now));
+    // a tombstone may only be dropped if no older file that is not merged can hold the key,
+    // otherwise the key would come back when the files are replayed
+    let has_older_kept_file =
+        |file_id: u64| matches!(oldest_kept_file_id, Some(kept_id) if kept_id < file_id);
+    let mut tombstones: HashMap<Vec<u8>, Record> = HashMap::new();
+    for file_id in &task.file_ids {
+        if has_older_kept_file(*file_id) {
+            collect_tombstones(&dir, *file_id, now, &mut tombstones)?;
+        }
+    }
+    tombstones.retain(|key, _| !indexes.contains_key(key));
+    // an expired set hides older values of its key just like a tombstone
+    for (key, index) in &expired_entries {
+        if has_older_kept_file(index.file_id) {
+            tombstones.insert(
+                key.clone(),
+                Record {
+                    command: Command::Remove,
+                    tstamp: now,
+                    expire_at: 0,
+                    key: key.clone(),
+                    value: Vec::new(),
+                },
+            );
+        }
+    }
+
+    // read the old files sequentially, with handles of our own so that no file cursor is shared
+    live_entries.sort_by_key(|(_, index)| (index.file_id, index.value_pos));
+    let mut sources: BTreeMap<u64, File> = BTreeMap::new();
+    let compact_path = compact_path(&dir, task.compact_file_id);
+    let mut compact_file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .open(&compact_path)?;
+    write_segment_header(&mut compact_file)?;
+    let mut compact_file = io::BufWriter::new(compact_file);
+    let mut pos = SEGMENT_HEADER_SIZE;
+    let mut hint_entries = Vec::with_capacity(live_entries.len() + tombstones.len());
+    let mut moved_entries = Vec::with_capacity(live_entries.len());
+    for (key, index) in live_entries {
+        let source = match sources.entry(index.file_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(File::open(dir.join(format!("{}.db", index.file_id)))?)
+            }
+        };
+        source.seek(std::io::SeekFrom::Start(index.value_pos))?;
+        // tricky, use io::copy to copy the record
+        io::copy(&mut source.take(index.value_sz), &mut compact_file)?;
+        let new_index = Index {
+            file_id: task.compact_file_id,
+            value_sz: index.value_sz,
+            value_pos: pos,
+            expire_at: index.expire_at,
+        };
+        pos += index.value_sz;
+        hint_entries.push(HintEntry {
+            key: key.clone(),
+            file_id: new_index.file_id,
+            offset: new_index.value_pos,
+            size: new_index.value_sz,
+            expire_at: new_index.expire_at,
+            removed: false,
+        });
+        moved_entries.push((key, index, new_index));
+    }
+    for (key, record) in tombstones {
+        let buf = record.encode();
+        compact_file.write_all(&buf)?;
+        hint_entries.push(HintEntry {
+            key,
+            file_id: task.compact_file_id,
+            offset: pos,
+            size: buf.len() as u64,
+            expire_at: 0,
+            removed: true,
+        });
+        pos += buf.len() as u64;
+    }
+    let compact_file = compact_file.into_inner().map_err(|e| e.into_error())?;
+    // the old files held nothing but garbage, there is no need to keep an empty file around
+    let compact_file = if hint_entries.is_empty() {
+        drop(compact_file);
+        fs::remove_file(&compact_path)?;
+        None
+    } else {
+        // the hint must not describe data that could still be lost, and N.db only appears
+        // once both are complete, so that an open never finds a torn file that is not the newest
+        compact_file.sync_all()?;
+        write_hint_file(&dir, task.compact_file_id, pos, &hint_entries)?;
+        fs::rename(
+            &compact_path,
+            dir.join(format!("{}.db", task.compact_file_id)),
+        )?;
+        sync_dir(&dir)?;
+        Some(compact_file)
+    };
+
+    let retired_ids = {
+        let mut db = db.lock().unwrap();
+        // readers must find the merged file before any index points to it,
+        // and the old files are only removed once no index points to them anymore
+        if let Some(compact_file) = compact_file {
+            map_segment(&db.maps, task.compact_file_id, &compact_file)?;
+            db.files
+                .insert(task.compact_file_id, Arc::new(compact_file));
+        }
+        // a reader sees the keys in the old files or in the merged one, never a file that is gone
+        let indexes = db.indexes.clone();
+        let stale_size = indexes.update(|| {
+            let mut stale_size = 0;
+            for (key, old_index, new_index) in moved_entries {
+                // the key may have been overwritten or removed while it was copied
+                match indexes.get_current(&key) {
+                    Some(index) if index == old_index => {
+                        indexes.insert(key, new_index);
+                    }
+                    _ => stale_size += new_index.value_sz,
+                }
+            }
+            // expired keys are not copied, their index would point to a removed file
+            for (key, old_index) in expired_entries {
+                if indexes.get_current(&key) == Some(old_index) {
+                    indexes.remove(&key);
+                }
+            }
+            for file_id in &task.file_ids {
+                db.maps.remove(file_id);
+                db.files.remove(file_id);
+                db.segments.remove(file_id);
+            }
+            stale_size
+        });
+        // renamed under the lock, so that the last snapshot pinning a file finds it to remove
+        let mut retired_ids = Vec::new();
+        for file_id in &task.file_ids {
+            if db.pins.contains_key(file_id) {
+                match db.retire(*file_id) {
+                    Ok(()) => retired_ids.push(*file_id),
+                    Err(e) => log::error!("retire compacted file {}.db failed: {:?}", file_id, e),
+                }
+            }
+        }
+        if db.files.contains_key(&task.compact_file_id) {
+            db.segments.insert(
+                task.compact_file_id,
+                SegmentStats {
+                    size: pos,
+                    // the tombstones kept here are still needed, they do not count as garbage
+                    garbage: stale_size,
+                },
+            );
+        }
+        db.compaction = None;
+        retired_ids
+    };
+    // the merged file is in use now, failing to remove an old file only wastes space
+    for file_id in task.file_ids {
+        let removed = match retired_ids.contains(&file_id) {
+            true => Ok(()),
+            false => fs::remove_file(dir.join(format!("{}.db", file_id))).map_err(KvsError::from),
+        };
+        if let Err(e) = removed.and_then(|_| remove_hint_file(&dir, file_id)) {
+            log::error!("remove compacted file {}.db failed: {:?}", file_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Keep the last tombstone of every key removed in log file `file_id`,
+/// a set that has expired by `now` counts as a remove.
+fn collect_tombstones(
+    dir: &Path,
+    file_id: u64,
+    now: u64,
+    tombstones: &mut HashMap<Vec<u8>, Record>,
+) -> Result<()> {
+    let file = File::open(dir.join(format!("{}.db", file_id)))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = io::BufReader::new(file);
+    reader.seek(std::io::SeekFrom::Start(SEGMENT_HEADER_SIZE))?;
+    let mut records = RecordReader::new(reader, file_id, file_len);
+    while let Some((_, _, record)) = records.next_record()? {
+        match record.command {
+            Command::Remove => {
+                tombstones.insert(record.key.clone(), record);
+            }
+            Command::Set if is_expired(record.expire_at, now) => {
+                let tombstone = Record {
+                    command: Command::Remove,
+                    expire_at: 0,
+                    value: Vec::new(),
+                    ..record
+                };
+                tombstones.insert(tombstone.key.clone(), tombstone);
+            }
+            Command::Set => {
+                tombstones.remove(&record.key);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
 fn get_db_files_ids(dir: &Path) -> Result<Vec<u64>> {
@@ -259,56 +1422,404 @@ fn get_db_files_ids(dir: &Path) -> Result<Vec<u64>> {
     Ok(files_ids)
 }
 
-fn get_file_handles(dir: &Path, file_ids: &[u64]) -> Result<BTreeMap<u64, File>> {
+fn get_file_handles(dir: &Path, file_ids: &[u64], read_only: bool) -> Result<BTreeMap<u64, File>> {
     let mut handles = BTreeMap::new();
     for file_id in file_ids {
-        handles.insert(*file_id, generate_new_file(dir, *file_id)?);
+        let file = match read_only {
+            true => File::open(dir.join(format!("{}.db", file_id)))?,
+            false => open_log_file(dir, *file_id)?,
+        };
+        handles.insert(*file_id, file);
     }
     Ok(handles)
 }
 
-fn build_indexes(file_handles: &mut BTreeMap<u64, File>) -> Result<(HashMap<String, Index>, u64)> {
-    let mut indexes = HashMap::new();
-    let mut uncompacted_size: u64 = 0;
+/// Apply the record found at `pos` of log file `file_id` to the index,
+/// and account the bytes it makes useless as garbage of the file they are in.
+/// A set that has expired by `now` is applied as a remove.
+fn apply_record(
+    indexes: &KeyDir,
+    segments: &mut BTreeMap<u64, SegmentStats>,
+    file_id: u64,
+    pos: u64,
+    size: u64,
+    record: Record,
+    now: u64,
+) {
+    let mut add_garbage = |file_id: u64, size: u64| {
+        if let Some(stats) = segments.get_mut(&file_id) {
+            stats.garbage += size;
+        }
+    };
+    match record.command {
+        Command::Set if !is_expired(record.expire_at, now) => {
+            let index = Index {
+                file_id,
+                value_sz: size,
+                value_pos: pos,
+                expire_at: record.expire_at,
+            };
+            if let Some(old_index) = indexes.insert(record.key, index) {
+                add_garbage(old_index.file_id, old_index.value_sz);
+            }
+        }
+        Command::Set | Command::Remove => {
+            if let Some(old_index) = indexes.remove(&record.key) {
+                add_garbage(old_index.file_id, old_index.value_sz);
+            }
+            add_garbage(file_id, size);
+        }
+        Command::BatchBegin | Command::BatchCommit => add_garbage(file_id, size),
+        Command::Get => {}
+    }
+}
+
+fn build_indexes(
+    dir: &Path,
+    file_handles: &mut BTreeMap<u64, File>,
+    read_only: bool,
+) -> Result<(KeyDir, BTreeMap<u64, SegmentStats>)> {
+    let indexes = KeyDir::new();
+    let mut segments: BTreeMap<u64, SegmentStats> = BTreeMap::new();
+    let active_file_id = file_handles.keys().next_back().cloned();
+    let now = now_micros()?;
     // loop all file in order instead of using timestamp to choose new record to build index
     for (file_id, file) in file_handles.iter() {
-        let mut pos = 0;
-        // maybe use buffer will be better,
-        let mut records_stream =
-            serde_json::Deserializer::from_reader(file.deref()).into_iter::<Record>();
-        // tricky, if use for record in records_stream,
-        // records_stream.byte_offset() will not work because records_stream has been moved
-        while let Some(record) = records_stream.next() {
-            let record = record?;
-            // get offset from stream instead of file
-            let new_pos = records_stream.byte_offset();
-            match record.command {
-                Command::Set => {
-                    if let Some(old_index) = indexes.insert(
-                        record.key,
-                        Index {
-                            file_id: *file_id,
-                            value_sz: new_pos as u64 - pos,
-                            value_pos: pos,
-                        },
-                    ) {
-                        uncompacted_size += old_index.value_sz;
+        let file_len = file.metadata()?.len();
+        segments.insert(
+            *file_id,
+            SegmentStats {
+                size: file_len,
+                garbage: 0,
+            },
+        );
+        // immutable files written by compaction only hold live sets and tombstones, their hint is enough
+        if Some(*file_id) != active_file_id {
+            if let Some(hint_entries) = read_hint_file(dir, *file_id, file_len)? {
+                for entry in hint_entries {
+                    let expired = !entry.removed && is_expired(entry.expire_at, now);
+                    let old_index = if entry.removed || expired {
+                        indexes.remove(&entry.key)
+                    } else {
+                        let index = Index {
+                            file_id: entry.file_id,
+                            value_sz: entry.size,
+                            value_pos: entry.offset,
+                            expire_at: entry.expire_at,
+                        };
+                        indexes.insert(entry.key, index)
+                    };
+                    if let Some(old_index) = old_index {
+                        if let Some(stats) = segments.get_mut(&old_index.file_id) {
+                            stats.garbage += old_index.value_sz;
+                        }
+                    }
+                    if expired {
+                        segments.get_mut(file_id).unwrap().garbage += entry.size;
                     }
                 }
-                Command::Remove => {
-                    if let Some(old_index) = indexes.remove(&record.key) {
-                        uncompacted_size += old_index.value_sz;
+                continue;
+            }
+        }
+        let mut reader = file;
+        reader.seek(std::io::SeekFrom::Start(SEGMENT_HEADER_SIZE))?;
+        // every record is checked by its crc, the first bad one fails the open
+        let mut records = RecordReader::new(io::BufReader::new(reader), *file_id, file_len);
+        // records of a write batch are held back until its commit record is read
+        let mut batch_pos: Option<u64> = None;
+        let mut batch_records = Vec::new();
+        let torn = loop {
+            let (pos, size, record) = match records.next_record() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => match batch_pos {
+                    Some(batch_pos) => {
+                        break Some((batch_pos, "uncommitted write batch".to_string()))
                     }
+                    None => break None,
+                },
+                // only the active file is appended to, so a bad record there is a write
+                // that was torn by a crash, and nothing after it has been acknowledged
+                Err(KvsError::Corrupted { reason, .. }) => {
+                    break Some((batch_pos.unwrap_or_else(|| records.pos()), reason));
+                }
+                Err(e) => return Err(e),
+            };
+            match (&record.command, batch_pos) {
+                (Command::BatchBegin, None) => {
+                    batch_pos = Some(pos);
+                    batch_records.push((pos, size, record));
+                }
+                (Command::BatchCommit, Some(_)) => {
+                    batch_pos = None;
+                    batch_records.push((pos, size, record));
+                    for (pos, size, record) in batch_records.drain(..) {
+                        apply_record(&indexes, &mut segments, *file_id, pos, size, record, now);
+                    }
+                }
+                (Command::BatchBegin, Some(_)) | (Command::BatchCommit, None) => {
+                    break Some((pos, "unexpected write batch record".to_string()));
                 }
-                _ => {}
+                (_, Some(_)) => batch_records.push((pos, size, record)),
+                (_, None) => {
+                    apply_record(&indexes, &mut segments, *file_id, pos, size, record, now)
+                }
+            }
+        };
+        if let Some((valid_len, reason)) = torn {
+            if Some(*file_id) != active_file_id {
+                return Err(KvsError::Corrupted {
+                    file_id: *file_id,
+                    offset: valid_len,
+                    reason,
+                    backtrace: Backtrace::force_capture(),
+                });
+            }
+            if read_only {
+                // the shared lock keeps writers out, so the tail was torn by a crash,
+                // it is left for the next writable open to cut off and is not indexed
+                log::warn!(
+                    "{}.db is torn after offset {} ({}), the rest of it is not indexed",
+                    file_id,
+                    valid_len,
+                    reason
+                );
+                continue;
+            }
+            log::warn!(
+                "truncate torn tail of {}.db: drop {} bytes after offset {} ({})",
+                file_id,
+                file_len - valid_len,
+                valid_len,
+                reason
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+            segments.get_mut(file_id).unwrap().size = valid_len;
+        }
+    }
+    Ok((indexes, segments))
+}
+
+/// Bring every log file to the current binary format before it is indexed.
+fn upgrade_segments(dir: &Path, file_ids: &[u64]) -> Result<()> {
+    for file_id in file_ids {
+        let file = open_log_file(dir, *file_id)?;
+        let format = match read_segment_format(&file, *file_id) {
+            // the process died while writing the header of the newest file
+            Err(KvsError::Corrupted { .. }) if Some(file_id) == file_ids.last() => {
+                log::warn!("reset torn segment header of {}.db", file_id);
+                file.set_len(0)?;
+                SegmentFormat::Empty
+            }
+            format => format?,
+        };
+        match format {
+            SegmentFormat::Binary => {}
+            // the process died right after creating the file
+            SegmentFormat::Empty => {
+                let mut writer = &file;
+                writer.seek(std::io::SeekFrom::Start(0))?;
+                write_segment_header(&mut writer)?;
+            }
+            SegmentFormat::OldBinary(version) => {
+                log::info!(
+                    "migrate log file {}.db from format version {}",
+                    file_id,
+                    version
+                );
+                drop(file);
+                // the record offsets change, so does the hint
+                remove_hint_file(dir, *file_id)?;
+                migrate_binary_segment(
+                    &dir.join(format!("{}.db", file_id)),
+                    *file_id,
+                    version,
+                    Some(file_id) == file_ids.last(),
+                )?;
+            }
+            SegmentFormat::LegacyJson => {
+                log::info!("migrate json log file {}.db to binary format", file_id);
+                drop(file);
+                migrate_legacy_segment(&dir.join(format!("{}.db", file_id)))?;
             }
-            pos = new_pos as u64;
         }
     }
-    Ok((indexes, uncompacted_size))
+    Ok(())
 }
 
-fn generate_new_file(path: &Path, file_id: u64) -> Result<File> {
+/// Check that every log file is in the current binary format, a read-only store cannot upgrade them.
+fn check_segments(dir: &Path, file_ids: &[u64]) -> Result<()> {
+    for file_id in file_ids {
+        let file = File::open(dir.join(format!("{}.db", file_id)))?;
+        match read_segment_format(&file, *file_id)? {
+            SegmentFormat::Binary => {}
+            // the newest file of a writer that has just created it
+            SegmentFormat::Empty if Some(file_id) == file_ids.last() => {}
+            _ => {
+                return Err(
+                    anyhow!("{}.db has to be upgraded by a writable open first", file_id).into(),
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Take the advisory lock of the directory: shared by the read-only stores, exclusive for a writer.
+fn lock_dir(dir: &Path, shared: bool) -> Result<File> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    let locked = match shared {
+        true => FileExt::try_lock_shared(&file),
+        false => FileExt::try_lock_exclusive(&file),
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(KvsError::Locked {
+            dir: dir.to_owned(),
+            backtrace: Backtrace::force_capture(),
+        }),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn check_writable(db: &KvDB) -> Result<()> {
+    if db.options.read_only {
+        return Err(KvsError::ReadOnly {
+            backtrace: Backtrace::force_capture(),
+        });
+    }
+    Ok(())
+}
+
+fn remove_hint_file(dir: &Path, file_id: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, file_id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn retired_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", file_id, RETIRED_EXTENSION))
+}
+
+fn remove_retired_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().unwrap_or_default() == RETIRED_EXTENSION {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn compact_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.{}", file_id, COMPACT_EXTENSION))
+}
+
+/// Remove the output of a compaction that did not finish, and its hint if it was written before the crash.
+fn remove_partial_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().unwrap_or_default() != COMPACT_EXTENSION {
+            continue;
+        }
+        log::warn!("remove unfinished compaction output {:?}", path);
+        if let Some(file_id) = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            if !dir.join(format!("{}.db", file_id)).exists() {
+                remove_hint_file(dir, file_id)?;
+            }
+        }
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Make the renames and removals in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// directories cannot be opened as files, the renames are made durable by the file system
+#[cfg(windows)]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The record `index` points to, in the map of its file.
+fn mapped_record<'a>(map: &'a Mmap, index: &Index) -> Result<&'a [u8]> {
+    map.get(index.value_pos, index.value_sz)
+        .ok_or_else(|| KvsError::Corrupted {
+            file_id: index.file_id,
+            offset: index.value_pos,
+            reason: "record past the end of the file".to_string(),
+            backtrace: Backtrace::force_capture(),
+        })
+}
+
+fn decode_value(index: &Index, bytes: &[u8]) -> Result<Vec<u8>> {
+    let record = Record::decode(bytes).map_err(|reason| KvsError::Corrupted {
+        file_id: index.file_id,
+        offset: index.value_pos,
+        reason,
+        backtrace: Backtrace::force_capture(),
+    })?;
+    Ok(record.value)
+}
+
+// map an immutable file, if the platform can
+fn map_segment(maps: &SkipMap<u64, Arc<Mmap>>, file_id: u64, file: &File) -> Result<()> {
+    if let Some(map) = Mmap::map(file)? {
+        maps.insert(file_id, Arc::new(map));
+    }
+    Ok(())
+}
+
+// read exactly `buf.len()` bytes at `offset`, without moving the cursor of the file
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// 0 means that the key never expires
+fn is_expired(expire_at: u64, now: u64) -> bool {
+    expire_at != 0 && expire_at <= now
+}
+
+fn now_micros() -> Result<u64> {
+    Ok(time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)?
+        .as_micros() as u64)
+}
+
+fn open_log_file(path: &Path, file_id: u64) -> Result<File> {
     let file_path = path.join(format!("{}.db", file_id));
     let file_handle = fs::OpenOptions::new()
         .write(true)
@@ -318,6 +1829,8 @@ fn generate_new_file(path: &Path, file_id: u64) -> Result<File> {
     Ok(file_handle)
 }
 
-fn get_last_file(file_handles: &BTreeMap<u64, File>, active_file_id: u64) -> Result<&File> {
-    Ok(file_handles.get(&active_file_id).unwrap())
+fn create_log_file(path: &Path, file_id: u64) -> Result<File> {
+    let mut file_handle = open_log_file(path, file_id)?;
+    write_segment_header(&mut file_handle)?;
+    Ok(file_handle)
 }
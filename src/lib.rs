@@ -11,11 +11,18 @@ mod redis_protocol;
 pub mod thread_pool;
 pub mod utils;
 
+pub use kvs::cache::*;
 pub use kvs::client::*;
+pub use kvs::durability::*;
 pub use kvs::engine::*;
 pub use kvs::error::*;
+pub use kvs::lsm_engine::*;
+pub use kvs::memory_engine::*;
+pub use kvs::meta::*;
+pub use kvs::migrate::*;
 pub use kvs::protocol::*;
 pub use kvs::server::*;
 pub use kvs::sled_engine::*;
+pub use kvs::snapshot::*;
 pub use kvs::store::*;
 pub use redis_protocol::*;
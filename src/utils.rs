@@ -1,9 +1,9 @@
 use anyhow::anyhow;
 use anyhow::Result;
 use slog::Drain;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
+use std::path::Path;
+
+use crate::EngineMeta;
 
 pub fn get_root_logger(process: String) -> slog::Logger {
     let decorator = slog_term::TermDecorator::new().stderr().build();
@@ -12,42 +12,13 @@ pub fn get_root_logger(process: String) -> slog::Logger {
     slog::Logger::root(drain, o!("process" => process))
 }
 
-pub fn get_last_engine() -> Option<String> {
-    if let Ok(file) = File::open("last_engine.txt") {
-        let mut file = BufReader::new(file);
-        let mut line = String::new();
-        file.read_line(&mut line).unwrap();
-        return Some(line.trim().to_string());
-    } else {
-        None
-    }
-}
-
-pub fn write_engine_to_file(engine: &str) -> Result<()> {
-    let mut file = File::create("last_engine.txt")?;
-    file.write_all(engine.as_bytes())?;
-    Ok(())
-}
-
-pub fn get_engine(last_engine: Option<String>, config_engine: Option<String>) -> Result<String> {
-    let engine: String;
-    if let Some(last_engine) = last_engine {
-        if let Some(config_engine) = config_engine {
-            if config_engine != last_engine {
-                return Err(anyhow!("engine not match lase used"));
-            } else {
-                engine = config_engine;
-            }
-        } else {
-            engine = last_engine;
-        }
-    } else if let Some(config_engine) = config_engine {
-        engine = config_engine;
-    } else {
-        engine = "kvs".to_string();
+/// Engine to open in `dir`: the one asked for, else the one the directory was written by, else kvs.
+/// Opening the engine checks that it matches the directory.
+pub fn get_engine(dir: &Path, config_engine: Option<String>) -> Result<String> {
+    if let Some(engine) = config_engine {
+        return Ok(engine);
     }
-    write_engine_to_file(&engine)?;
-    Ok(engine)
+    Ok(EngineMeta::engine_of(dir)?.unwrap_or_else(|| "kvs".to_string()))
 }
 
 pub fn parse_ip_port(ip_port: &str) -> Result<(std::net::IpAddr, u16)> {
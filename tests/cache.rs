@@ -0,0 +1,137 @@
+use kvs::{
+    get_engine_by_name, CacheStats, CachedEngine, KvsEngine, MemoryKvsEngine, Result, WriteBatch,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Reads of a cached value should be counted as hits, and the first read of a key as a miss
+#[test]
+fn count_hits_and_misses() -> Result<()> {
+    let engine = CachedEngine::new(MemoryKvsEngine::default(), 1024);
+    engine.set("key1", "value1")?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    for _ in 0..3 {
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    }
+    // absent keys are not cached
+    assert_eq!(engine.get("key2")?, None);
+    assert_eq!(engine.get("key2")?, None);
+    assert_eq!(
+        engine.stats(),
+        CacheStats {
+            hits: 3,
+            misses: 3,
+            entries: 1,
+            size: 10,
+        }
+    );
+    Ok(())
+}
+
+// The least recently read values should be evicted once the cache holds more bytes than its size
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    // room for three keys of 4 bytes with values of 6 bytes
+    let engine = CachedEngine::new(MemoryKvsEngine::default(), 30);
+    for key in ["key1", "key2", "key3", "key4"] {
+        engine.set(key, "value0")?;
+    }
+    for key in ["key1", "key2", "key3"] {
+        engine.get(key)?;
+    }
+    engine.get("key1")?;
+    // key2 is the least recently used
+    engine.get("key4")?;
+    let stats = engine.stats();
+    assert_eq!((stats.entries, stats.size), (3, 30));
+
+    let misses = engine.stats().misses;
+    for key in ["key1", "key3", "key4"] {
+        engine.get(key)?;
+    }
+    assert_eq!(engine.stats().misses, misses);
+    engine.get("key2")?;
+    assert_eq!(engine.stats().misses, misses + 1);
+
+    // a value larger than the whole cache is not cached
+    engine.set("large", &"x".repeat(100))?;
+    engine.get("large")?;
+    assert_eq!(engine.stats().entries, 3);
+    Ok(())
+}
+
+// Every write through the cache should drop the values it replaces
+#[test]
+fn invalidate_on_writes() -> Result<()> {
+    let engine = CachedEngine::new(MemoryKvsEngine::default(), 1024);
+    let cached = |key: &str, value: &str| -> Result<()> {
+        engine.set(key, value)?;
+        assert_eq!(engine.get(key)?, Some(value.to_owned()));
+        Ok(())
+    };
+
+    cached("key1", "value1")?;
+    engine.set("key1", "value2")?;
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+
+    engine.remove("key1")?;
+    assert_eq!(engine.get("key1")?, None);
+
+    cached("key2", "value1")?;
+    cached("key3", "value1")?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key3");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3")?, None);
+
+    cached("key4", "value1")?;
+    assert!(engine.compare_and_swap("key4", Some("value1"), Some("value2"))?);
+    assert_eq!(engine.get("key4")?, Some("value2".to_owned()));
+
+    cached("key5", "value1")?;
+    engine.set_with_ttl("key5", "value2", Duration::from_millis(100))?;
+    assert_eq!(engine.get("key5")?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key5")?, Some("value2".to_owned()));
+    // the cached value expires with the key
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(engine.get("key5")?, None);
+    Ok(())
+}
+
+// A value read while it is overwritten should never stay in the cache once the write returns
+#[test]
+fn no_stale_value_after_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Arc::new(CachedEngine::new(
+        get_engine_by_name("kvs", temp_dir.path())?,
+        1024 * 1024,
+    ));
+    engine.set("key", "0")?;
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let done = done.clone();
+            thread::spawn(move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    engine.get("key")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 1..=500 {
+        engine.set("key", &i.to_string())?;
+        assert_eq!(engine.get("key")?, Some(i.to_string()));
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(engine.get("key")?, Some("500".to_owned()));
+    Ok(())
+}
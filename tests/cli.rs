@@ -212,12 +212,29 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server --durability` should refuse a policy it does not know
+#[test]
+fn cli_invalid_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--durability", "sometimes", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid durability"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
+    cli_access_server_with_args(engine, addr, &[]);
+}
+
+fn cli_access_server_with_args(engine: &str, addr: &str, server_args: &[&str]) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -299,6 +316,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(server_args)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -322,6 +340,84 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key", "--prefix", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key2\tvalue3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value4", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key4", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key2",
+            "value4",
+            "--expected",
+            "value1",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value mismatch"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key2",
+            "value4",
+            "--expected",
+            "value3",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key2", "--expected", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +431,63 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_with_cache() {
+    cli_access_server_with_args("kvs", "127.0.0.1:4008", &["--cache-size", "1048576"]);
+}
+
+// `kvs migrate` should copy the keys into the other engine, which the server then accepts
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["-e", "kvs", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", ".", "--from", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["migrate", ".", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys migrated"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["-e", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    let meta = fs::read_to_string(temp_dir.path().join("kvs.meta")).unwrap();
+    assert!(meta.contains("\"engine\": \"sled\""));
+}
+
+// `kvs` should not open the directory of a running server
+#[test]
+fn cli_locked_by_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
@@ -0,0 +1,60 @@
+use kvs::{get_engine_with_durability, Durability, KvsError, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The policies should be parsed from the values of `kvs-server --durability`, and printed back the same
+#[test]
+fn parse_durability() -> Result<()> {
+    for (value, durability) in [
+        ("always", Durability::Always),
+        ("every:16", Durability::EveryN(16)),
+        ("interval:100", Durability::Interval(100)),
+        ("os", Durability::OsBuffered),
+    ] {
+        assert_eq!(value.parse::<Durability>()?, durability);
+        assert_eq!(durability.to_string(), value);
+    }
+    for value in ["", "never", "every:0", "every:x", "interval:", "always:1"] {
+        assert!(matches!(
+            value.parse::<Durability>(),
+            Err(KvsError::InvalidDurability { .. })
+        ));
+    }
+    Ok(())
+}
+
+// Whatever the policy, every acknowledged write should be found once the engine is reopened
+#[test]
+fn reopen_with_every_durability() -> Result<()> {
+    for engine in ["kvs", "sled", "lsm"] {
+        for durability in [
+            Durability::Always,
+            Durability::EveryN(3),
+            Durability::Interval(10),
+            Durability::OsBuffered,
+        ] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let store = get_engine_with_durability(engine, temp_dir.path(), durability)?;
+            for i in 0..10 {
+                store.set(&format!("key{}", i), &format!("value{}", i))?;
+            }
+            store.remove("key3")?;
+            // give the background sync a few rounds
+            thread::sleep(Duration::from_millis(50));
+            store.set("key1", "value1b")?;
+            drop(store);
+
+            let store = get_engine_with_durability(engine, temp_dir.path(), durability)?;
+            for i in [0, 2, 4, 9] {
+                assert_eq!(
+                    store.get(&format!("key{}", i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+            assert_eq!(store.get("key1")?, Some("value1b".to_owned()));
+            assert_eq!(store.get("key3")?, None);
+        }
+    }
+    Ok(())
+}
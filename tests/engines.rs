@@ -0,0 +1,549 @@
+use kvs::{
+    CachedEngine, KvStore, KvsEngineFactory, KvsError, LsmKvsEngine, MemoryKvsEngine, Result,
+    SledKvsEngine, WriteBatch,
+};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Every test here runs against every engine listed in the `engine_tests!` call at the end,
+// a new engine only has to be added there to be covered.
+macro_rules! engine_tests {
+    ($($module:ident: $engine:ty),* $(,)?) => {
+        $(
+            mod $module {
+                use super::*;
+
+                engine_tests!(@tests $engine;
+                    get_stored_value,
+                    overwrite_value,
+                    get_non_existent_value,
+                    remove_non_existent_key,
+                    remove_key,
+                    concurrent_set,
+                    concurrent_get,
+                    large_values,
+                    scan_keys_in_order,
+                    write_batch,
+                    compare_and_swap,
+                    expire_keys_with_ttl,
+                    binary_keys_and_values,
+                    snapshot_ignores_later_writes,
+                    snapshot_sees_whole_batches,
+                );
+            }
+        )*
+    };
+    (@tests $engine:ty; $($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $test() -> Result<()> {
+                super::$test::<$engine>()
+            }
+        )*
+    };
+}
+
+fn open<E: KvsEngineFactory>(temp_dir: &TempDir) -> Result<E> {
+    E::open(temp_dir.path())
+}
+
+// Should get previously stored value
+fn get_stored_value<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+fn overwrite_value<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+
+    engine.set("key1", "value1")?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    engine.set("key1", "value2")?;
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+    engine.set("key1", "value3")?;
+    assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+fn get_non_existent_value<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+
+    engine.set("key1", "value1")?;
+    assert_eq!(engine.get("key2")?, None);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get("key2")?, None);
+
+    Ok(())
+}
+
+// Removing a key that was never set, or that is already removed, should be a `KeyNotFound` error
+fn remove_non_existent_key<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    assert!(matches!(
+        engine.remove("key1"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+    engine.set("key1", "value1")?;
+    engine.remove("key1")?;
+    assert!(matches!(
+        engine.remove("key1"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+    Ok(())
+}
+
+// A removed key should stay removed after a reopen
+fn remove_key<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    assert!(engine.remove("key1").is_ok());
+    assert_eq!(engine.get("key1")?, None);
+
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn concurrent_set<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    let handles: Vec<_> = (0..1000)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                engine
+                    .set(&format!("key{}", i), &format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..1000 {
+        assert_eq!(
+            engine.get(&format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    for i in 0..1000 {
+        assert_eq!(
+            engine.get(&format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+fn concurrent_get<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    for i in 0..100 {
+        engine.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    check_concurrent_get(&engine);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    check_concurrent_get(&engine);
+
+    Ok(())
+}
+
+fn check_concurrent_get<E: KvsEngineFactory>(engine: &E) {
+    let handles: Vec<_> = (0..100)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key_id = (i + thread_id) % 100;
+                    assert_eq!(
+                        engine.get(&format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// Keys and values of several megabytes, and empty ones, should be stored as they are
+fn large_values<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (b"empty".to_vec(), Vec::new()),
+        (vec![b'k'; 64 << 10], b"value of a large key".to_vec()),
+        (
+            b"1m".to_vec(),
+            (0..1 << 20).map(|n| (n % 251) as u8).collect(),
+        ),
+        (
+            b"8m".to_vec(),
+            (0..8 << 20).map(|n| (n % 253) as u8).collect(),
+        ),
+    ];
+    for (key, value) in &pairs {
+        engine.set_bytes(key, value)?;
+    }
+    for (key, value) in &pairs {
+        assert_eq!(engine.get_bytes(key)?.as_ref(), Some(value));
+    }
+
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    for (key, value) in &pairs {
+        assert_eq!(engine.get_bytes(key)?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// Scans should return the live keys in order, also after a reopen
+fn scan_keys_in_order<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    for (key, value) in [
+        ("c", "5"),
+        ("abc", "3"),
+        ("a", "1"),
+        ("b", "4"),
+        ("ab", "2"),
+    ] {
+        engine.set(key, value)?;
+    }
+    engine.set("bb", "6")?;
+    engine.remove("bb")?;
+    check_scan(&engine)?;
+
+    drop(engine);
+    check_scan(&open::<E>(&temp_dir)?)
+}
+
+fn check_scan<E: KvsEngineFactory>(engine: &E) -> Result<()> {
+    let scan = |start: &str, end: Option<&str>, limit: Option<usize>| -> Result<Vec<String>> {
+        engine
+            .scan(start, end, limit)?
+            .map(|pair| pair.map(|(key, value)| format!("{}={}", key, value)))
+            .collect()
+    };
+    assert_eq!(
+        scan("a", None, None)?,
+        vec!["a=1", "ab=2", "abc=3", "b=4", "c=5"]
+    );
+    assert_eq!(scan("ab", Some("b"), None)?, vec!["ab=2", "abc=3"]);
+    assert_eq!(
+        scan("aa", Some("c"), Some(3))?,
+        vec!["ab=2", "abc=3", "b=4"]
+    );
+    assert_eq!(scan("c", Some("a"), None)?, Vec::<String>::new());
+    let prefix: Vec<(String, String)> = engine.scan_prefix("ab")?.collect::<Result<_>>()?;
+    assert_eq!(
+        prefix,
+        vec![
+            ("ab".to_owned(), "2".to_owned()),
+            ("abc".to_owned(), "3".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// A write batch should apply its sets and removes in order
+fn write_batch<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value3")
+        .remove("key2")
+        .set("key3", "value4")
+        .remove("key4")
+        .set("key5", "value5")
+        .remove("key5");
+    engine.write_batch(batch)?;
+    let check = |engine: &E| -> Result<()> {
+        assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+        assert_eq!(engine.get("key2")?, None);
+        assert_eq!(engine.get("key3")?, Some("value4".to_owned()));
+        assert_eq!(engine.get("key4")?, None);
+        assert_eq!(engine.get("key5")?, None);
+        Ok(())
+    };
+    check(&engine)?;
+
+    drop(engine);
+    check(&open::<E>(&temp_dir)?)
+}
+
+// Compare-and-swap should only write when the current value is the expected one
+fn compare_and_swap<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    assert!(engine.set_if_absent("key1", "value1")?);
+    assert!(!engine.set_if_absent("key1", "value2")?);
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+
+    assert!(!engine.compare_and_swap("key1", Some("value2"), Some("value3"))?);
+    assert!(engine.compare_and_swap("key1", Some("value1"), Some("value3"))?);
+    assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+
+    assert!(!engine.remove_if_equal("key1", "value1")?);
+    assert!(engine.remove_if_equal("key1", "value3")?);
+    assert_eq!(engine.get("key1")?, None);
+    assert!(engine.compare_and_swap("key2", None, None)?);
+    assert_eq!(engine.get("key2")?, None);
+
+    // concurrent increments through compare-and-swap should not lose any update
+    engine.set("counter", "0")?;
+    let barrier = Arc::new(Barrier::new(4));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    loop {
+                        let current = engine.get("counter").unwrap().unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if engine
+                            .compare_and_swap("counter", Some(&current), Some(&next))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get("counter")?, Some("400".to_owned()));
+
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.get("counter")?, Some("400".to_owned()));
+    Ok(())
+}
+
+// Keys set with a ttl should read as absent once it has passed
+fn expire_keys_with_ttl<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    engine.set_with_ttl("key1", "value1", Duration::from_millis(200))?;
+    engine.set_with_ttl("key2", "value2", Duration::from_secs(3600))?;
+    engine.set("key3", "value3")?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    let ttl = engine.ttl("key2")?.expect("key2 expires");
+    assert!(ttl <= Duration::from_secs(3600) && ttl > Duration::from_secs(3500));
+    assert_eq!(engine.ttl("key3")?, None);
+    assert!(matches!(
+        engine.ttl("key4"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key1")?, None);
+    assert!(matches!(
+        engine.ttl("key1"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+    assert!(matches!(
+        engine.remove("key1"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+    let keys: Vec<String> = engine
+        .scan("key", None, None)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["key2", "key3"]);
+    assert!(engine.set_if_absent("key1", "value4")?);
+    assert_eq!(engine.ttl("key1")?, None);
+
+    // a write without ttl makes the key persistent again
+    engine.set("key2", "value5")?;
+    assert_eq!(engine.ttl("key2")?, None);
+
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.ttl("key1")?, None);
+    assert_eq!(engine.get("key2")?, Some("value5".to_owned()));
+    assert_eq!(engine.ttl("key2")?, None);
+    Ok(())
+}
+
+// Keys and values that are not utf-8 should be stored as they are
+fn binary_keys_and_values<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    let key1 = vec![0xff, 0x00, 0xfe];
+    let key2 = vec![0xff, 0x01];
+    let value1 = vec![0x80, 0x00, 0x00, 0xc3];
+    engine.set_bytes(&key1, &value1)?;
+    engine.set_bytes(&key2, &[])?;
+    engine.set_bytes(b"key3", b"value3")?;
+    assert_eq!(engine.get_bytes(&key1)?, Some(value1.clone()));
+    assert_eq!(engine.get_bytes(&key2)?, Some(vec![]));
+    // the string api does not mangle data that is not utf-8
+    assert!(matches!(
+        engine.get(&String::from_utf8_lossy(&key1)),
+        Ok(None)
+    ));
+    engine.set_bytes(b"key4", &value1)?;
+    assert!(matches!(engine.get("key4"), Err(KvsError::Utf8 { .. })));
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> =
+        engine.scan_prefix_bytes(&[0xff])?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![(key1.clone(), value1), (key2.clone(), vec![])]);
+
+    let mut batch = WriteBatch::new();
+    batch.set(key2.clone(), vec![0xff; 3]).remove(key1.clone());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get_bytes(&key1)?, None);
+    assert!(engine.compare_and_swap_bytes(&key2, Some(&[0xff; 3]), Some(&[0x00]))?);
+    assert_eq!(engine.get_bytes(&key2)?, Some(vec![0x00]));
+    assert!(matches!(
+        engine.remove_bytes(&key1),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+
+    drop(engine);
+    let engine = open::<E>(&temp_dir)?;
+    assert_eq!(engine.get_bytes(&key2)?, Some(vec![0x00]));
+    assert_eq!(engine.get_bytes(&key1)?, None);
+    assert_eq!(engine.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep reading the values of the time it was taken, while the engine moves on
+fn snapshot_ignores_later_writes<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    for i in 0..100 {
+        engine.set(&format!("key{:03}", i), "old")?;
+    }
+    let snapshot = engine.snapshot()?;
+    for i in 0..100 {
+        match i % 3 {
+            0 => engine.set(&format!("key{:03}", i), "new")?,
+            1 => engine.remove(&format!("key{:03}", i))?,
+            _ => {}
+        }
+    }
+    engine.set("key100", "new")?;
+
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(&format!("key{:03}", i))?,
+            Some("old".to_owned())
+        );
+    }
+    assert_eq!(snapshot.get("key100")?, None);
+    let pairs: Vec<(String, String)> = snapshot
+        .scan("key010", None, Some(5))?
+        .collect::<Result<_>>()?;
+    let expected: Vec<(String, String)> = (10..15)
+        .map(|i| (format!("key{:03}", i), "old".to_owned()))
+        .collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(snapshot.scan("", None, None)?.count(), 100);
+
+    assert_eq!(engine.get("key000")?, Some("new".to_owned()));
+    assert_eq!(engine.get("key001")?, None);
+    assert_eq!(engine.get("key100")?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Snapshots taken while batches are written should see every batch whole or not at all
+fn snapshot_sees_whole_batches<E: KvsEngineFactory>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open::<E>(&temp_dir)?;
+    let keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+    let writer = {
+        let engine = engine.clone();
+        let keys = keys.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 0..200 {
+                let mut batch = WriteBatch::new();
+                for key in &keys {
+                    batch.set(key.as_str(), format!("{}", round));
+                }
+                engine.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..50 {
+        let snapshot = engine.snapshot()?;
+        let values: Vec<Option<String>> = keys
+            .iter()
+            .map(|key| snapshot.get(key))
+            .collect::<Result<_>>()?;
+        assert!(values.iter().all(|value| *value == values[0]));
+        let scanned: Vec<String> = snapshot
+            .scan("", None, None)?
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        match &values[0] {
+            Some(value) => assert_eq!(scanned, vec![value.clone(); keys.len()]),
+            None => assert!(scanned.is_empty()),
+        }
+    }
+    writer.join().unwrap()
+}
+
+engine_tests! {
+    kv_store: KvStore,
+    sled: SledKvsEngine,
+    memory: MemoryKvsEngine,
+    lsm: LsmKvsEngine,
+    cached: CachedEngine<KvStore>,
+}
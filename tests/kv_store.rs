@@ -1,213 +1,890 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Durability, KvStore, KvStoreOptions, KvsEngine, KvsEngineFactory, KvsError, Result, WriteBatch,
+};
 use ntest::timeout;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-// Should get previously stored value
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
 #[test]
-fn get_stored_value() -> Result<()> {
+#[timeout(30000)]
+fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(&key, &value)?;
+        }
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
 
-    Ok(())
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(&key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
 }
 
-// Should overwrite existent value
+fn db_files(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let mut files: Vec<(u64, PathBuf)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("db"))
+        .map(|path| {
+            let file_id = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (file_id, path)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// A flipped bit in a record should be detected, when the store is opened or when the record is read
 #[test]
-fn overwrite_value() -> Result<()> {
+fn detect_corrupted_record() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    // overwrite until compaction leaves an immutable file behind the active one
+    let mut iter = 0;
+    while db_files(temp_dir.path()).len() < 2 {
+        store.set(&format!("key{}", iter % 10), &format!("value{}", iter))?;
+        iter += 1;
+    }
+    drop(store);
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let (file_id, path) = db_files(temp_dir.path()).remove(0);
+    let mut content = fs::read(&path)?;
+    let middle = content.len() / 2;
+    content[middle] ^= 0x01;
+    fs::write(&path, content)?;
 
-    // Open from disk again and check persistent data
+    // the hint of the compacted file lets open skip its records, so the bad one is found on read
+    let store = KvStore::open(temp_dir.path())?;
+    let corrupted_reads = (0..10)
+        .filter(|key_id| {
+            matches!(
+                store.get(&format!("key{}", key_id)),
+                Err(KvsError::Corrupted { .. })
+            )
+        })
+        .count();
+    assert_eq!(corrupted_reads, 1);
     drop(store);
+
+    // without the hint, open reads every record and refuses the file
+    fs::remove_file(temp_dir.path().join(format!("{}.hint", file_id)))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corrupted { .. }) => Ok(()),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("corrupted record not detected"),
+    }
+}
+
+// Logs written in the old json format should still be readable, and be rewritten in binary format
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy_log = concat!(
+        r#"{"command":"Set","tstamp":1,"key":"key1","value":"value1"}"#,
+        r#"{"command":"Set","tstamp":2,"key":"key2","value":"value2"}"#,
+        r#"{"command":"Remove","tstamp":3,"key":"key1","value":""}"#
+    );
+    fs::write(temp_dir.path().join("1.db"), legacy_log)?;
+
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3", "value3")?;
+    drop(store);
 
+    assert!(fs::read(temp_dir.path().join("1.db"))?.starts_with(b"KVSLOG"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key
+// Cut the active file at random offsets, as if the process died in the middle of a write.
+// Every record written completely before the cut should survive the reopen, and the torn tail is dropped.
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn recover_from_torn_write() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    let (_, active_path) = db_files(temp_dir.path()).pop().unwrap();
+    let mut record_ends = Vec::new();
+    for i in 0..50 {
+        store.set(&format!("key{}", i), &format!("value{}", i))?;
+        record_ends.push(fs::metadata(&active_path)?.len());
+    }
+    drop(store);
+    let content = fs::read(&active_path)?;
+    let file_name = active_path.file_name().unwrap();
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    // xorshift, so that a failing offset can be reproduced
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next_offset = |len: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % len as u64) as usize
+    };
+    for round in 0..30 {
+        let cut = next_offset(content.len());
+        let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut torn = content[..cut].to_vec();
+        // half of the crashes also leave garbage from a partially flushed page,
+        // the 8-byte segment header itself is written at once when the file is created
+        if round % 2 == 1 && cut >= 8 {
+            torn.extend_from_slice(&[0xab; 7]);
+        }
+        fs::write(crash_dir.path().join(file_name), &torn)?;
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+        let store = KvStore::open(crash_dir.path())?;
+        let survived = record_ends.iter().filter(|end| **end <= cut as u64).count();
+        for i in 0..50 {
+            let expected = if i < survived {
+                Some(format!("value{}", i))
+            } else {
+                None
+            };
+            assert_eq!(store.get(&format!("key{}", i))?, expected, "cut at {}", cut);
+        }
 
+        // the store should keep appending after the truncated tail
+        store.set("after_crash", "value")?;
+        drop(store);
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.get("after_crash")?, Some("value".to_owned()));
+        if survived > 0 {
+            assert_eq!(
+                store.get(&format!("key{}", survived - 1))?,
+                Some(format!("value{}", survived - 1))
+            );
+        }
+    }
     Ok(())
 }
 
+// Compaction should leave a hint file next to the compacted log file,
+// and the store should open the same with a valid, a stale or a missing hint
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn open_with_hint_files() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
-    Ok(())
+    let mut iter = 0;
+    while db_files(temp_dir.path()).len() < 2 {
+        store.set(&format!("key{}", iter % 20), &format!("value{}", iter))?;
+        iter += 1;
+    }
+    store.set("key_in_active_file", "value")?;
+    drop(store);
+
+    let check = |dir: &Path| -> Result<()> {
+        let store = KvStore::open(dir)?;
+        for key_id in 0..20 {
+            let last_iter = (0..iter).rfind(|i| i % 20 == key_id).unwrap();
+            assert_eq!(
+                store.get(&format!("key{}", key_id))?,
+                Some(format!("value{}", last_iter))
+            );
+        }
+        assert_eq!(store.get("key_in_active_file")?, Some("value".to_owned()));
+        Ok(())
+    };
+
+    let (compacted_id, _) = db_files(temp_dir.path()).remove(0);
+    let hint_path = temp_dir.path().join(format!("{}.hint", compacted_id));
+    assert!(hint_path.exists());
+    check(temp_dir.path())?;
+
+    let mut hint = fs::read(&hint_path)?;
+    let last = hint.len() - 1;
+    hint[last] ^= 0x01;
+    fs::write(&hint_path, hint)?;
+    check(temp_dir.path())?;
+
+    fs::remove_file(&hint_path)?;
+    check(temp_dir.path())
 }
 
+// Compaction runs in the background, writes and reads that race with it should not be lost
 #[test]
-fn remove_key() -> Result<()> {
+#[timeout(60000)]
+fn write_during_background_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..2000 {
+                let key = format!("key{}_{}", thread_id, iter % 50);
+                let value = format!("value{}", iter);
+                store.set(&key, &value).unwrap();
+                assert_eq!(store.get(&key).unwrap(), Some(value));
+                // removed keys must not come back when their old records are merged
+                if iter % 7 == 0 {
+                    store.remove(&key).unwrap();
+                    assert_eq!(store.get(&key).unwrap(), None);
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..50 {
+                let last_iter = (0..2000).rfind(|i| i % 50 == key_id).unwrap();
+                let expected = if last_iter % 7 == 0 {
+                    None
+                } else {
+                    Some(format!("value{}", last_iter))
+                };
+                assert_eq!(
+                    store.get(&format!("key{}_{}", thread_id, key_id))?,
+                    expected
+                );
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// The active log file should roll over once it reaches the max segment size
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_threshold(u64::MAX)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..200 {
+        store.set(&format!("key{}", key_id), &format!("value{}", key_id))?;
+    }
+    let files = db_files(temp_dir.path());
+    assert!(files.len() > 5);
+    for (_, path) in &files[..files.len() - 1] {
+        assert!(fs::metadata(path)?.len() < 1024 + 64);
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(&format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Values in the files that are not appended to anymore, rolled over or merged by compaction,
+// should be read from their memory maps as well as from the active file
 #[test]
-#[timeout(30000)]
-fn compaction() -> Result<()> {
+fn read_immutable_segments() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_threshold(u64::MAX)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let value =
+        |key_id: u32, round: u32| vec![(key_id % 256) as u8; (key_id + round) as usize % 100];
+    let check = |store: &KvStore, round: u32| -> Result<()> {
+        for key_id in 0..200 {
+            assert_eq!(
+                store.get_bytes(format!("key{}", key_id).as_bytes())?,
+                Some(value(key_id, round))
+            );
+        }
+        Ok(())
+    };
+    for key_id in 0..200 {
+        store.set_bytes(format!("key{}", key_id).as_bytes(), &value(key_id, 0))?;
+    }
+    check(&store, 0)?;
+    store.compact()?;
+    check(&store, 0)?;
+    for key_id in (0..200).step_by(3) {
+        store.set_bytes(format!("key{}", key_id).as_bytes(), &value(key_id, 1))?;
+    }
+    let check_mixed = |store: &KvStore| -> Result<()> {
+        for key_id in 0..200 {
+            let round = if key_id % 3 == 0 { 1 } else { 0 };
+            assert_eq!(
+                store.get_bytes(format!("key{}", key_id).as_bytes())?,
+                Some(value(key_id, round))
+            );
+        }
+        Ok(())
+    };
+    check_mixed(&store)?;
+    drop(store);
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    check_mixed(&KvStore::open_with_options(temp_dir.path(), options())?)?;
+    check_mixed(&KvStore::open_read_only(temp_dir.path())?)
+}
+
+// Only the log files with enough garbage should be compacted, without bringing back removed keys
+#[test]
+#[timeout(30000)]
+fn compact_subset_of_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_ratio(0.5)
+            .garbage_threshold(2048)
     };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("gone", "value")?;
+    for key_id in 0..100 {
+        store.set(&format!("cold{}", key_id), &format!("value{}", key_id))?;
+    }
+    let files = db_files(temp_dir.path());
+    let cold_files = &files[..files.len() - 1];
+    assert!(cold_files.len() > 2);
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+    store.remove("gone")?;
+    // the hot log files fill up with garbage, keep writing until they have been merged
+    let mut last_iter = None;
+    for iter in 0..100000 {
+        store.set("hot", &format!("value{}", iter))?;
+        if iter > 1000 && db_files(temp_dir.path()).len() <= cold_files.len() + 4 {
+            last_iter = Some(iter);
+            break;
         }
+    }
+    let last_iter = last_iter.expect("hot log files are not compacted");
+    // wait for the running compaction
+    drop(store);
+    for (_, path) in cold_files {
+        assert!(path.exists());
+    }
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("gone")?, None);
+    assert_eq!(store.get("hot")?, Some(format!("value{}", last_iter)));
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(&format!("cold{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A manual compaction should merge every log file
+#[test]
+#[timeout(30000)]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_threshold(u64::MAX)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for iter in 0..500 {
+        store.set(&format!("key{}", iter % 50), &format!("value{}", iter))?;
+        if iter % 50 == 7 {
+            store.remove("key7")?;
         }
-        // Compaction triggered
+    }
+    assert!(db_files(temp_dir.path()).len() > 5);
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+    store.compact()?;
+    assert_eq!(db_files(temp_dir.path()).len(), 2);
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..50 {
+            let expected = if key_id == 7 {
+                None
+            } else {
+                Some(format!("value{}", 450 + key_id))
+            };
+            assert_eq!(store.get(&format!("key{}", key_id))?, expected);
         }
-        return Ok(());
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
+}
+
+// A compaction interrupted by a crash should leave nothing that stops the store from opening
+#[test]
+fn open_after_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_threshold(u64::MAX)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for iter in 0..200 {
+        store.set(&format!("key{}", iter % 20), &format!("value{}", iter))?;
     }
+    drop(store);
 
-    panic!("No compaction detected");
+    // the merged file, half written, next to the files it merges
+    let (first_id, first_path) = db_files(temp_dir.path()).remove(0);
+    let (last_id, _) = db_files(temp_dir.path()).pop().unwrap();
+    let content = fs::read(&first_path)?;
+    let compact_path = temp_dir.path().join(format!("{}.compact", last_id + 1));
+    fs::write(&compact_path, &content[..content.len() / 2])?;
+    // its hint may have been written just before the crash, only its presence matters here
+    fs::copy(
+        temp_dir.path().join(format!("{}.db", first_id)),
+        temp_dir.path().join(format!("{}.hint", last_id + 1)),
+    )?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..20 {
+            assert_eq!(
+                store.get(&format!("key{}", key_id))?,
+                Some(format!("value{}", 180 + key_id))
+            );
+        }
+        Ok(())
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(!compact_path.exists());
+    assert!(!temp_dir
+        .path()
+        .join(format!("{}.hint", last_id + 1))
+        .exists());
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
 }
 
+// Readers running against writers, rollover and compaction should always see a value written for their key
 #[test]
-fn concurrent_set() -> Result<()> {
+#[timeout(60000)]
+fn concurrent_get_set_stress() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
+    let options = KvStoreOptions::new()
+        .max_segment_size(16 * 1024)
+        .garbage_threshold(8 * 1024);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(&format!("key{}", key_id), &format!("key{}:0", key_id))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(12));
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            barrier.wait();
+            for iter in 1..=2000 {
+                let key_id = (iter * 4 + thread_id) % 100;
+                store
+                    .set(
+                        &format!("key{}", key_id),
+                        &format!("key{}:{}", key_id, iter),
+                    )
+                    .unwrap();
+            }
+        }));
+    }
+    for thread_id in 0..8 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
+        handles.push(thread::spawn(move || {
             barrier.wait();
-        });
+            for iter in 0..5000 {
+                let key = format!("key{}", (iter * 7 + thread_id) % 100);
+                let value = store.get(&key).unwrap().expect("key is never removed");
+                assert!(value.starts_with(&format!("{}:", key)));
+            }
+        }));
     }
-    barrier.wait();
-
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    for handle in handles {
+        handle.join().unwrap();
     }
 
-    // Open from disk again and check persistent data
+    // every key has been written last by the writer that owns it
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let last_iter = (1..=2000).rfind(|iter| (iter * 4 + key_id % 4) % 100 == key_id);
+            let expected = match last_iter {
+                Some(iter) => format!("key{}:{}", key_id, iter),
+                None => format!("key{}:0", key_id),
+            };
+            assert_eq!(store.get(&format!("key{}", key_id))?, Some(expected));
+        }
+        Ok(())
+    };
+    check(&store)?;
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
-
-    Ok(())
+    check(&KvStore::open(temp_dir.path())?)
 }
 
+// Writers committed together should each get their own result, in the order of the group,
+// and every acknowledged write be found after reopening
 #[test]
-fn concurrent_get() -> Result<()> {
+#[timeout(60000)]
+fn group_commit_concurrent_writers() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(16 * 1024)
+            .durability(Durability::Always)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        store.set(&format!("shared{}", key_id), "value")?;
     }
 
+    let barrier = Arc::new(Barrier::new(8));
     let mut handles = Vec::new();
-    for thread_id in 0..100 {
+    for thread_id in 0..8 {
         let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || -> Result<usize> {
+            barrier.wait();
+            let mut removed = 0;
+            for key_id in 0..100 {
+                let key = format!("key{}:{}", thread_id, key_id);
+                store.set(&key, &format!("value{}", key_id))?;
+                if key_id % 2 == 0 {
+                    store.remove(&key)?;
+                }
+                let mut batch = WriteBatch::new();
+                batch.set(format!("batch{}:{}", thread_id, key_id), "value");
+                store.write_batch(batch)?;
+                match store.remove(&format!("shared{}", key_id)) {
+                    Ok(()) => removed += 1,
+                    Err(KvsError::KeyNotFound { .. }) => {}
+                    Err(e) => return Err(e),
+                }
             }
-        });
-        handles.push(handle);
+            Ok(removed)
+        }));
     }
+    let mut removed = 0;
     for handle in handles {
-        handle.join().unwrap();
+        removed += handle.join().unwrap()?;
     }
+    // each shared key is removed by exactly one of the writers
+    assert_eq!(removed, 100);
 
-    // Open from disk again and check persistent data
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            for key_id in 0..100 {
+                let expected = match key_id % 2 {
+                    0 => None,
+                    _ => Some(format!("value{}", key_id)),
+                };
+                assert_eq!(
+                    store.get(&format!("key{}:{}", thread_id, key_id))?,
+                    expected
+                );
+                assert!(store
+                    .get(&format!("batch{}:{}", thread_id, key_id))?
+                    .is_some());
+            }
+        }
+        assert_eq!(store.scan("shared", Some("sharee"), None)?.count(), 0);
+        Ok(())
+    };
+    check(&store)?;
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    check(&KvStore::open_with_options(temp_dir.path(), options())?)
+}
+
+// Readers running while batches are written, and compacted away, should see each batch whole or not at all
+#[test]
+#[timeout(60000)]
+fn readers_see_whole_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4 * 1024)
+        .garbage_threshold(0);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let write_round = |round: u64| -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id), round.to_string());
+        }
+        store.write_batch(batch)
+    };
+    write_round(0)?;
+
+    let done = Arc::new(AtomicBool::new(false));
     let mut handles = Vec::new();
-    for thread_id in 0..100 {
+    for _ in 0..4 {
         let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+        let done = done.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            let round = |value: Option<String>| -> u64 { value.unwrap().parse().unwrap() };
+            while !done.load(Ordering::SeqCst) {
+                // key9 is set after key0 in every batch, it is never behind
+                let first = round(store.get("key0")?);
+                let last = round(store.get("key9")?);
+                assert!(last >= first, "key9 at {} behind key0 at {}", last, first);
+                let values: Vec<u64> = store
+                    .scan("key", None, None)?
+                    .map(|pair| pair.map(|(_, value)| round(Some(value))))
+                    .collect::<Result<_>>()?;
+                assert_eq!(values.len(), 10);
+                assert!(
+                    values.iter().all(|value| *value == values[0]),
+                    "{:?}",
+                    values
                 );
             }
-        });
-        handles.push(handle);
+            Ok(())
+        }));
+    }
+    for round in 1..2000 {
+        write_round(round)?;
     }
+    done.store(true, Ordering::SeqCst);
     for handle in handles {
-        handle.join().unwrap();
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// Compaction should drop the expired keys, and keep the ttl of the others across a reopen
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            &format!("key{}", key_id),
+            &value,
+            Duration::from_millis(200),
+        )?;
+    }
+    store.set_with_ttl("long", "value1", Duration::from_secs(3600))?;
+    thread::sleep(Duration::from_millis(300));
+    store.compact()?;
+    let entries = WalkDir::new(temp_dir.path()).into_iter();
+    let dir_size: walkdir::Result<u64> = entries
+        .map(|res| {
+            res.and_then(|entry| entry.metadata())
+                .map(|metadata| metadata.len())
+        })
+        .sum();
+    assert!(dir_size.expect("fail to get directory size") < 10 * 1024);
+    for key_id in 0..100 {
+        assert_eq!(store.get(&format!("key{}", key_id))?, None);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("long")?, Some("value1".to_owned()));
+    assert!(store.ttl("long")?.expect("long expires") > Duration::from_secs(3500));
+    Ok(())
+}
+
+// A log file written before records had an expiry should be rewritten in the current format
+#[test]
+fn open_format_v1_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let v1_record = |command: u8, key: &str, value: &str| {
+        let mut buf = vec![0; 4];
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&1u64.to_le_bytes());
+        buf.push(command);
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    };
+    let mut log = b"KVSLOG".to_vec();
+    log.extend_from_slice(&1u16.to_le_bytes());
+    log.extend(v1_record(1, "key1", "value1"));
+    log.extend(v1_record(1, "key2", "value2"));
+    log.extend(v1_record(3, "key1", ""));
+    fs::write(temp_dir.path().join("1.db"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.ttl("key2")?, None);
+    store.set("key3", "value3")?;
+    drop(store);
+
+    assert!(fs::read(temp_dir.path().join("1.db"))?.starts_with(b"KVSLOG\x02\x00"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Keys and values that are not utf-8 should be copied as they are by compaction
+#[test]
+fn compact_binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(&[0xff, 0x00, 0xfe], &[0x80, 0x00, 0x00, 0xc3])?;
+    store.set_bytes(&[0xff, 0x01], &[])?;
+    store.set_bytes(&[0xff, 0x01], &[0x00])?;
+    store.remove_bytes(&[0xff, 0x00, 0xfe])?;
+    store.set("key3", "value3")?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&[0xff, 0x01])?, Some(vec![0x00]));
+    assert_eq!(store.get_bytes(&[0xff, 0x00, 0xfe])?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A write batch cut by a crash anywhere before its commit record should not be applied at all
+#[test]
+fn recover_from_torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+    let (_, path) = db_files(temp_dir.path()).pop().unwrap();
+    let len_before_batch = fs::metadata(&path)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value2").set("key2", "value2");
+    store.write_batch(batch)?;
+    drop(store);
+    let content = fs::read(&path)?;
+
+    for cut in len_before_batch as usize..content.len() {
+        fs::write(&path, &content[..cut])?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        // the uncommitted records are dropped, so that later writes are not mistaken for the batch
+        store.set("key3", "value3")?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2")?, None);
+        assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+        drop(store);
     }
+    Ok(())
+}
+
+// A directory opened for writes should not be opened again, read-only opens should share it
+#[test]
+fn lock_data_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    drop(store);
+
+    let reader1 = KvStore::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader1.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key1")?, Some("value1".to_owned()));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    drop(reader1);
+    drop(reader2);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
 
+// A read-only store should refuse every write, and leave the files as they are
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+    // a torn tail is left alone
+    let (_, path) = db_files(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&path)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 1)?;
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert!(matches!(
+        store.set("key3", "value3"),
+        Err(KvsError::ReadOnly { .. })
+    ));
+    assert!(matches!(
+        store.remove("key1"),
+        Err(KvsError::ReadOnly { .. })
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly { .. })));
+    drop(store);
+    assert_eq!(fs::metadata(&path)?.len(), len - 1);
+
+    // an empty directory opens as an empty store
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.scan("", None, None)?.count(), 0);
     Ok(())
 }
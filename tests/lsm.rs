@@ -0,0 +1,136 @@
+use kvs::{KvsEngine, LsmKvsEngine, LsmOptions, Result};
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// tiny memtables and tables, so that a few hundred keys go through every level
+fn open(path: &Path) -> Result<LsmKvsEngine> {
+    let options = LsmOptions::new()
+        .memtable_size(1024)
+        .table_size(2048)
+        .level0_tables(2)
+        .level_size(4096, 4);
+    LsmKvsEngine::open_with_options(path, options)
+}
+
+fn files_with_extension(path: &Path, extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+// Keys flushed to tables and compacted into deeper levels should read as their latest value,
+// removed ones as absent, before and after a reopen
+#[test]
+fn read_through_levels() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..500 {
+            engine.set(&format!("key{:04}", i), &format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in (0..500).step_by(3) {
+        engine.remove(&format!("key{:04}", i))?;
+    }
+    assert!(files_with_extension(temp_dir.path(), "sst").len() > 2);
+
+    let check = |engine: &LsmKvsEngine| -> Result<()> {
+        for i in 0..500 {
+            let expected = (i % 3 != 0).then(|| format!("value{}-2", i));
+            assert_eq!(engine.get(&format!("key{:04}", i))?, expected);
+        }
+        let pairs: Vec<(String, String)> = engine
+            .scan("key0100", Some("key0200"), None)?
+            .collect::<Result<_>>()?;
+        let expected: Vec<(String, String)> = (100..200)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (format!("key{:04}", i), format!("value{}-2", i)))
+            .collect();
+        assert_eq!(pairs, expected);
+        Ok(())
+    };
+    check(&engine)?;
+    drop(engine);
+    check(&open(temp_dir.path())?)
+}
+
+// A full compaction should leave the live keys only, and drop the tombstones and expired values
+#[test]
+fn compact_drops_dead_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for i in 0..200 {
+        engine.set(&format!("key{:04}", i), "value")?;
+    }
+    for i in 0..100 {
+        engine.remove(&format!("key{:04}", i))?;
+    }
+    engine.set_with_ttl("expiring", "value", Duration::from_millis(1))?;
+    std::thread::sleep(Duration::from_millis(10));
+    engine.compact()?;
+
+    let pairs: Vec<(String, String)> = engine.scan("", None, None)?.collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0].0, "key0100");
+    // 100 small pairs fit in a single table of 2 KiB or so
+    assert!(files_with_extension(temp_dir.path(), "sst").len() <= 2);
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key0050")?, None);
+    assert_eq!(engine.get("key0150")?, Some("value".to_owned()));
+    assert_eq!(engine.get("expiring")?, None);
+    Ok(())
+}
+
+// A write cut short by a crash at the end of the log should be dropped, the writes before it kept
+#[test]
+fn recover_torn_log_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), LsmOptions::new())?;
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    drop(engine);
+
+    let wals = files_with_extension(temp_dir.path(), "wal");
+    let wal = temp_dir.path().join(wals.last().unwrap());
+    let file = OpenOptions::new().write(true).open(&wal)?;
+    file.set_len(file.metadata()?.len() - 3)?;
+    drop(file);
+
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), LsmOptions::new())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+    engine.set("key3", "value3")?;
+    drop(engine);
+
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), LsmOptions::new())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A table left behind by an interrupted flush or compaction is not in the manifest, and should be removed
+#[test]
+fn remove_orphan_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for i in 0..100 {
+        engine.set(&format!("key{:04}", i), "value")?;
+    }
+    drop(engine);
+    let orphan = temp_dir.path().join("999999.sst");
+    fs::write(&orphan, b"not a table")?;
+
+    let engine = open(temp_dir.path())?;
+    assert!(!orphan.exists());
+    assert_eq!(engine.get("key0042")?, Some("value".to_owned()));
+    Ok(())
+}
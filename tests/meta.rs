@@ -0,0 +1,85 @@
+use kvs::{get_engine_by_name, EngineMeta, KvStore, KvsEngineFactory, KvsError, Result, META_FILE};
+use std::fs;
+use tempfile::TempDir;
+
+// The first engine opened in a directory should record itself there, and every other engine be refused
+#[test]
+fn refuse_other_engines() -> Result<()> {
+    for engine in ["kvs", "sled", "lsm"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        get_engine_by_name(engine, temp_dir.path())?.set("key1", "value1")?;
+        assert_eq!(EngineMeta::load(temp_dir.path())?.unwrap().engine, engine);
+
+        for other in ["kvs", "sled", "lsm"]
+            .iter()
+            .filter(|other| **other != engine)
+        {
+            assert!(matches!(
+                get_engine_by_name(other, temp_dir.path()),
+                Err(KvsError::EngineMismatch { .. })
+            ));
+        }
+        let engine = get_engine_by_name(engine, temp_dir.path())?;
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// The metadata should tell whether the engine is running, and when it was last shut down cleanly
+#[test]
+fn record_clean_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let meta = EngineMeta::load(temp_dir.path())?.unwrap();
+    assert!(meta.running);
+    assert_eq!(meta.last_clean_shutdown, None);
+
+    // every handle has to be gone
+    let handle = store.clone();
+    drop(store);
+    assert!(EngineMeta::load(temp_dir.path())?.unwrap().running);
+    drop(handle);
+    let closed = EngineMeta::load(temp_dir.path())?.unwrap();
+    assert!(!closed.running);
+    assert!(closed.last_clean_shutdown.is_some());
+    assert_eq!(closed.created_at, meta.created_at);
+    Ok(())
+}
+
+// A directory written in a newer format than the engine reads should not be opened
+#[test]
+fn refuse_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let meta_path = temp_dir.path().join(META_FILE);
+    let meta = fs::read_to_string(&meta_path)?;
+    let mut meta: serde_json::Value = serde_json::from_str(&meta)?;
+    meta["format_version"] = 999.into();
+    fs::write(&meta_path, meta.to_string())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedEngineFormat { version: 999, .. })
+    ));
+    Ok(())
+}
+
+// The engine marker of older versions should be honoured, and replaced by the metadata
+#[test]
+fn read_legacy_engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("last_engine.txt"), "sled")?;
+    assert_eq!(
+        EngineMeta::engine_of(temp_dir.path())?,
+        Some("sled".to_owned())
+    );
+    assert!(matches!(
+        get_engine_by_name("kvs", temp_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+
+    get_engine_by_name("sled", temp_dir.path())?;
+    assert!(!temp_dir.path().join("last_engine.txt").exists());
+    assert_eq!(EngineMeta::load(temp_dir.path())?.unwrap().engine, "sled");
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use kvs::{
+    get_engine_by_name, migrate, EngineMeta, KvStore, KvsEngine, KvsEngineFactory, KvsError,
+    Result, META_FILE,
+};
+use std::fs;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Every live key, with its ttl, should be found in the new engine, which becomes the engine of the directory
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // more keys than a batch, so that the copy takes several
+    for i in 0..2500 {
+        store.set(&format!("key{:04}", i), &format!("value{}", i))?;
+    }
+    store.remove("key0042")?;
+    store.set_bytes(&[0xff, 0x00], &[0x01])?;
+    store.set_with_ttl("expiring", "value", Duration::from_secs(3600))?;
+    drop(store);
+
+    let report = migrate(temp_dir.path(), "kvs", "sled")?;
+    assert_eq!(report.count, 2501);
+    let meta = EngineMeta::load(temp_dir.path())?.unwrap();
+    assert_eq!(meta.engine, "sled");
+    assert_eq!(meta.migrating_to, None);
+    assert!(!temp_dir.path().join("migrate.state").exists());
+
+    // and on to a third engine
+    assert_eq!(migrate(temp_dir.path(), "sled", "lsm")?, report);
+    let engine = get_engine_by_name("lsm", temp_dir.path())?;
+    assert_eq!(engine.get("key0041")?, Some("value41".to_owned()));
+    assert_eq!(engine.get("key0042")?, None);
+    assert_eq!(engine.get_bytes(&[0xff, 0x00])?, Some(vec![0x01]));
+    let ttl = engine.ttl("expiring")?.expect("the ttl should be migrated");
+    assert!(ttl > Duration::from_secs(3500));
+    Ok(())
+}
+
+// A migration into an engine that already holds keys, or that cannot run, should not start
+#[test]
+fn refuse_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1", "value1")?;
+    migrate(temp_dir.path(), "kvs", "lsm")?;
+
+    // the files of kvs are still there
+    for (from, to) in [("lsm", "kvs"), ("lsm", "lsm"), ("lsm", "memory")] {
+        assert!(matches!(
+            migrate(temp_dir.path(), from, to),
+            Err(KvsError::Migration { .. })
+        ));
+    }
+    let meta = EngineMeta::load(temp_dir.path())?.unwrap();
+    assert_eq!(meta.engine, "lsm");
+    assert_eq!(meta.migrating_to, None);
+    Ok(())
+}
+
+// A migration interrupted before its first batch is recorded should be resumed, not refused
+// because the target already holds some of the keys
+#[test]
+fn resume_interrupted_first_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    drop(store);
+
+    // as `migrate` leaves the directory when it stops in the middle of the first batch
+    let meta_path = temp_dir.path().join(META_FILE);
+    let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(&meta_path)?)?;
+    meta["migrating_to"] = "sled".into();
+    fs::write(&meta_path, meta.to_string())?;
+    let state = serde_json::json!({ "from": "kvs", "to": "sled", "copied_up_to": null });
+    fs::write(
+        temp_dir.path().join("migrate.state"),
+        serde_cbor::to_vec(&state)?,
+    )?;
+    let target = get_engine_by_name("sled", temp_dir.path())?;
+    target.set("key0", "value0")?;
+    target.set("key1", "value1")?;
+    drop(target);
+
+    let report = migrate(temp_dir.path(), "kvs", "sled")?;
+    assert_eq!(report.count, 10);
+    assert_eq!(EngineMeta::load(temp_dir.path())?.unwrap().engine, "sled");
+    assert!(!temp_dir.path().join("migrate.state").exists());
+    Ok(())
+}
+
+// A copy that does not match the source should be undone, so that the next migration starts over
+#[test]
+fn restart_after_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the first keys are recorded as copied, but one of them holds another value in the target
+    let meta_path = temp_dir.path().join(META_FILE);
+    let mut meta: serde_json::Value = serde_json::from_str(&fs::read_to_string(&meta_path)?)?;
+    meta["migrating_to"] = "sled".into();
+    fs::write(&meta_path, meta.to_string())?;
+    let state = serde_json::json!({
+        "from": "kvs",
+        "to": "sled",
+        "copied_up_to": serde_bytes::ByteBuf::from(b"key4".to_vec()),
+    });
+    fs::write(
+        temp_dir.path().join("migrate.state"),
+        serde_cbor::to_vec(&state)?,
+    )?;
+    let target = get_engine_by_name("sled", temp_dir.path())?;
+    for i in 0..5 {
+        target.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    target.set("key3", "other")?;
+    drop(target);
+
+    assert!(matches!(
+        migrate(temp_dir.path(), "kvs", "sled"),
+        Err(KvsError::Migration { .. })
+    ));
+    let meta = EngineMeta::load(temp_dir.path())?.unwrap();
+    assert_eq!(meta.engine, "kvs");
+    assert_eq!(meta.migrating_to, None);
+    assert!(!temp_dir.path().join("migrate.state").exists());
+
+    let report = migrate(temp_dir.path(), "kvs", "sled")?;
+    assert_eq!(report.count, 10);
+    let engine = get_engine_by_name("sled", temp_dir.path())?;
+    assert_eq!(engine.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Keys expiring while the engines are compared should not fail the migration
+#[test]
+fn migrate_expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // one expiring every millisecond, from before the copy until after the comparison
+    for i in 0..10000 {
+        store.set_with_ttl(&format!("key{:05}", i), "value", Duration::from_millis(i))?;
+    }
+    store.set("kept", "value")?;
+    drop(store);
+
+    migrate(temp_dir.path(), "kvs", "sled")?;
+    assert_eq!(EngineMeta::load(temp_dir.path())?.unwrap().engine, "sled");
+    let engine = get_engine_by_name("sled", temp_dir.path())?;
+    assert_eq!(engine.get("kept")?, Some("value".to_owned()));
+    Ok(())
+}
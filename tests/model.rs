@@ -0,0 +1,100 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    Get(String),
+    Reopen,
+    Compact,
+}
+
+// few keys so that most writes overwrite or remove a live value, and make garbage
+fn key() -> impl Strategy<Value = String> {
+    (0..8u8).prop_map(|key_id| format!("key{}", key_id))
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (key(), "[a-z]{0,64}").prop_map(|(key, value)| Op::Set(key, value)),
+        3 => key().prop_map(Op::Remove),
+        3 => key().prop_map(Op::Get),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+// tiny segments and thresholds, so that rollover and compaction run every few operations
+fn open_store(path: &Path) -> Result<KvStore> {
+    let options = KvStoreOptions::new()
+        .max_segment_size(256)
+        .garbage_ratio(0.3)
+        .garbage_threshold(128);
+    KvStore::open_with_options(path, options)
+}
+
+// tiny memtables and tables, so that flushes and compactions run every few operations
+fn open_lsm(path: &Path) -> Result<LsmKvsEngine> {
+    let options = LsmOptions::new()
+        .memtable_size(256)
+        .table_size(256)
+        .level0_tables(2)
+        .level_size(512, 2);
+    LsmKvsEngine::open_with_options(path, options)
+}
+
+// Apply the operations to the engine and to a `BTreeMap`, every read of the engine should match the map
+fn check_against_model<E: KvsEngine>(
+    ops: Vec<Op>,
+    open: impl Fn(&Path) -> Result<E>,
+    compact: impl Fn(&E) -> Result<()>,
+) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+    let mut model = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Set(key, value) => {
+                store.set(&key, &value)?;
+                model.insert(key, value);
+            }
+            Op::Remove(key) => match (store.remove(&key), model.remove(&key)) {
+                (Ok(()), Some(_)) => {}
+                (Err(KvsError::KeyNotFound { .. }), None) => {}
+                (result, expected) => {
+                    panic!("remove {}: got {:?}, model has {:?}", key, result, expected)
+                }
+            },
+            Op::Get(key) => assert_eq!(store.get(&key)?, model.get(&key).cloned(), "get {}", key),
+            Op::Reopen => {
+                drop(store);
+                store = open(temp_dir.path())?;
+            }
+            Op::Compact => compact(&store)?,
+        }
+    }
+
+    let pairs: Vec<(String, String)> = store.scan("", None, None)?.collect::<Result<_>>()?;
+    assert_eq!(pairs, model.into_iter().collect::<Vec<_>>());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // A failing sequence is shrunk to the fewest operations that still fail
+    #[test]
+    fn store_matches_model(ops in vec(op(), 1..200)) {
+        check_against_model(ops, open_store, KvStore::compact).unwrap();
+    }
+
+    #[test]
+    fn lsm_matches_model(ops in vec(op(), 1..200)) {
+        check_against_model(ops, open_lsm, LsmKvsEngine::compact).unwrap();
+    }
+}
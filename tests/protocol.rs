@@ -0,0 +1,429 @@
+use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+use kvs::utils::{get_root_logger, parse_ip_port};
+use kvs::{
+    get_engine_by_name, BytesScanIter, IKvsServer, KvStore, KvsClient, KvsEngine, KvsEngineFactory,
+    KvsServer, KvsSnapshot, MemoryKvsEngine,
+};
+use kvs::{
+    read_frame, write_frame, Command, Response, Result, ScanRange, WriteBatch, MAX_FRAME_SIZE,
+};
+use std::io::Cursor;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Several frames written back to back should be read one by one
+#[test]
+fn read_several_frames_from_one_buffer() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(
+        &mut buf,
+        &Command::Set(b"key1".to_vec(), b"value1".to_vec()),
+    )?;
+    write_frame(&mut buf, &Command::Get(b"key1".to_vec()))?;
+    write_frame(&mut buf, &Response::Success(Some(b"value1".to_vec())))?;
+
+    let mut reader = Cursor::new(buf);
+    match read_frame::<_, Command>(&mut reader)? {
+        Some(Command::Set(key, value)) => {
+            assert_eq!((key, value), (b"key1".to_vec(), b"value1".to_vec()))
+        }
+        other => panic!("unexpected frame: {:?}", other),
+    }
+    match read_frame::<_, Command>(&mut reader)? {
+        Some(Command::Get(key)) => assert_eq!(key, b"key1"),
+        other => panic!("unexpected frame: {:?}", other),
+    }
+    match read_frame::<_, Response>(&mut reader)? {
+        Some(Response::Success(value)) => assert_eq!(value, Some(b"value1".to_vec())),
+        other => panic!("unexpected frame: {:?}", other),
+    }
+    assert!(read_frame::<_, Command>(&mut reader)?.is_none());
+    Ok(())
+}
+
+// A frame cut in the middle should be an error instead of a clean end of stream
+#[test]
+fn read_truncated_frame() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Command::Get(b"key1".to_vec()))?;
+    for len in 1..buf.len() {
+        let mut reader = Cursor::new(&buf[..len]);
+        assert!(read_frame::<_, Command>(&mut reader).is_err());
+    }
+    Ok(())
+}
+
+// A header announcing more than MAX_FRAME_SIZE bytes should be refused without reading the payload,
+// and so should a message too large to be sent
+#[test]
+fn refuse_oversized_frame() -> Result<()> {
+    for len in [MAX_FRAME_SIZE as u32 + 1, u32::MAX] {
+        let mut reader = Cursor::new(len.to_be_bytes().to_vec());
+        assert!(read_frame::<_, Command>(&mut reader).is_err());
+    }
+    let mut buf = Vec::new();
+    let value = vec![0; MAX_FRAME_SIZE + 1];
+    assert!(write_frame(&mut buf, &Command::Set(b"key1".to_vec(), value)).is_err());
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// Binary values much larger than one tcp segment should go through the server unchanged
+#[test]
+fn send_multi_megabyte_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ip_port = parse_ip_port("127.0.0.1:4006")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        for (i, size) in [1 << 20, 4 << 20, 8 << 20].iter().enumerate() {
+            let key = format!("key{}", i).into_bytes();
+            let value: Vec<u8> = (0..*size).map(|n| (n % 251) as u8).collect();
+            assert_eq!(
+                client
+                    .send(&Command::Set(key.clone(), value.clone()))
+                    .unwrap(),
+                None
+            );
+            assert_eq!(client.send(&Command::Get(key)).unwrap(), Some(value));
+        }
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// Scans should go through the server, with a limit and with a prefix
+#[test]
+fn scan_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ip_port = parse_ip_port("127.0.0.1:4007")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        for key in ["key3", "key1", "other", "key2"] {
+            client
+                .send(&Command::Set(
+                    key.as_bytes().to_vec(),
+                    format!("{}-value", key).into_bytes(),
+                ))
+                .unwrap();
+        }
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+            pairs
+                .into_iter()
+                .map(|(key, _)| String::from_utf8(key).unwrap())
+                .collect()
+        };
+        let pairs = client
+            .scan(ScanRange::Range(b"key".to_vec(), None), None)
+            .unwrap();
+        assert_eq!(pairs[0], (b"key1".to_vec(), b"key1-value".to_vec()));
+        assert_eq!(keys(pairs), vec!["key1", "key2", "key3", "other"]);
+        let pairs = client
+            .scan(ScanRange::Range(b"key2".to_vec(), None), Some(2))
+            .unwrap();
+        assert_eq!(keys(pairs), vec!["key2", "key3"]);
+        let pairs = client
+            .scan(ScanRange::Prefix(b"key".to_vec()), Some(2))
+            .unwrap();
+        assert_eq!(keys(pairs), vec!["key1", "key2"]);
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// An engine whose scans fail after the first pair
+struct FailingScans(MemoryKvsEngine);
+
+impl KvsEngine for FailingScans {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.0.set_bytes(key, value)
+    }
+    fn set_bytes_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.0.set_bytes_with_ttl(key, value, ttl)
+    }
+    fn ttl_bytes(&self, key: &[u8]) -> Result<Option<Duration>> {
+        self.0.ttl_bytes(key)
+    }
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.0.get_bytes(key)
+    }
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.0.remove_bytes(key)
+    }
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.0.write_batch(batch)
+    }
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.0.compare_and_swap_bytes(key, expected, new)
+    }
+    fn scan_bytes(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<BytesScanIter> {
+        let pairs = self.0.scan_bytes(start, end, limit)?.take(1);
+        Ok(Box::new(pairs.chain(std::iter::once(Err(
+            anyhow::anyhow!("scan failed").into(),
+        )))))
+    }
+    fn snapshot(&self) -> Result<Box<dyn KvsSnapshot>> {
+        self.0.snapshot()
+    }
+}
+
+// A scan failing in the engine should be answered with an error, and the connection kept
+#[test]
+fn scan_error_through_server() -> Result<()> {
+    let ip_port = parse_ip_port("127.0.0.1:4011")?;
+    let server = KvsServer::new(
+        ip_port,
+        FailingScans(MemoryKvsEngine::default()),
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        client
+            .send(&Command::Set(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        for range in [
+            ScanRange::Range(b"key".to_vec(), None),
+            ScanRange::Prefix(b"key".to_vec()),
+        ] {
+            let err = client.scan(range, None).unwrap_err();
+            assert!(err.to_string().contains("scan failed"));
+        }
+        let value = client.send(&Command::Get(b"key1".to_vec())).unwrap();
+        assert_eq!(value, Some(b"value1".to_vec()));
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// A failed set with a ttl should be answered with an error, on a connection that stays usable
+#[test]
+fn set_with_ttl_error_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    let ip_port = parse_ip_port("127.0.0.1:4012")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open_read_only(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        let command = Command::SetWithTtl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_secs(60),
+        );
+        assert!(client.send(&command).is_err());
+        let value = client.send(&Command::Get(b"key1".to_vec())).unwrap();
+        assert_eq!(value, None);
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// A write batch should be applied by the server as a whole
+#[test]
+fn write_batch_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ip_port = parse_ip_port("127.0.0.1:4008")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        client
+            .send(&Command::Set(b"key1".to_vec(), b"value1".to_vec()))
+            .unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .remove(b"key1".to_vec())
+            .set(b"key2".to_vec(), b"value2".to_vec());
+        assert_eq!(client.send(&Command::Batch(batch)).unwrap(), None);
+        assert_eq!(client.send(&Command::Get(b"key1".to_vec())).unwrap(), None);
+        assert_eq!(
+            client.send(&Command::Get(b"key2".to_vec())).unwrap(),
+            Some(b"value2".to_vec())
+        );
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// Compare-and-swap requests should report whether the value was swapped
+#[test]
+fn compare_and_swap_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ip_port = parse_ip_port("127.0.0.1:4009")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        assert!(client
+            .compare_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))
+            .unwrap());
+        assert!(!client
+            .compare_and_swap(b"key1".to_vec(), None, Some(b"value2".to_vec()))
+            .unwrap());
+        assert!(client
+            .compare_and_swap(
+                b"key1".to_vec(),
+                Some(b"value1".to_vec()),
+                Some(b"value2".to_vec())
+            )
+            .unwrap());
+        assert_eq!(
+            client.send(&Command::Get(b"key1".to_vec())).unwrap(),
+            Some(b"value2".to_vec())
+        );
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// Keys set with a ttl through the server should report the time left and then expire
+#[test]
+fn ttl_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ip_port = parse_ip_port("127.0.0.1:4010")?;
+    let server = KvsServer::new(
+        ip_port,
+        KvStore::open(temp_dir.path())?,
+        RayonThreadPool::new(2)?,
+        get_root_logger("kvs-server".to_string()),
+    )?;
+    crossbeam::scope(|scope| {
+        scope.spawn(|_| server.run().unwrap());
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client =
+            KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+        client
+            .send(&Command::SetWithTtl(
+                b"key1".to_vec(),
+                b"value1".to_vec(),
+                Duration::from_millis(500),
+            ))
+            .unwrap();
+        client
+            .send(&Command::Set(b"key2".to_vec(), b"value2".to_vec()))
+            .unwrap();
+        assert!(client.ttl(b"key1".to_vec()).unwrap().unwrap() <= Duration::from_millis(500));
+        assert_eq!(client.ttl(b"key2".to_vec()).unwrap(), None);
+        assert!(client.ttl(b"key3".to_vec()).is_err());
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(client.send(&Command::Get(b"key1".to_vec())).unwrap(), None);
+        drop(client);
+        server.close();
+    })
+    .unwrap();
+    Ok(())
+}
+
+// An engine chosen by name at runtime should be served, and stay usable outside of the server
+#[test]
+fn serve_engine_chosen_at_runtime() -> Result<()> {
+    for (engine_name, port) in [
+        ("kvs", 4011),
+        ("sled", 4012),
+        ("memory", 4013),
+        ("lsm", 4014),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let ip_port = parse_ip_port(&format!("127.0.0.1:{}", port))?;
+        let engine = get_engine_by_name(engine_name, temp_dir.path())?;
+        let server = KvsServer::with_shared_engine(
+            ip_port,
+            engine.clone(),
+            RayonThreadPool::new(2)?,
+            get_root_logger("kvs-server".to_string()),
+        )?;
+        crossbeam::scope(|scope| {
+            scope.spawn(|_| server.run().unwrap());
+            thread::sleep(Duration::from_secs(1));
+
+            let mut client =
+                KvsClient::new(ip_port, get_root_logger("kvs-client".to_string())).unwrap();
+            client
+                .send(&Command::Set(b"key1".to_vec(), b"value1".to_vec()))
+                .unwrap();
+            assert_eq!(engine.get("key1").unwrap(), Some("value1".to_owned()));
+            engine.set("key2", "value2").unwrap();
+            assert_eq!(
+                client.send(&Command::Get(b"key2".to_vec())).unwrap(),
+                Some(b"value2".to_vec())
+            );
+            drop(client);
+            server.close();
+        })
+        .unwrap();
+    }
+    assert!(get_engine_by_name("unknown", TempDir::new().unwrap().path()).is_err());
+    Ok(())
+}
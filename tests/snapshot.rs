@@ -0,0 +1,103 @@
+use kvs::{KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn files_with_extension(path: &Path, extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+// Compaction should leave the log files an open snapshot reads, and remove them once it is dropped
+#[test]
+fn compaction_keeps_pinned_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .max_segment_size(1024)
+            .garbage_threshold(u64::MAX)
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..200 {
+        store.set(&format!("key{:03}", i), &format!("old{}", i))?;
+    }
+    let snapshot = store.snapshot()?;
+    for i in 0..200 {
+        store.set(&format!("key{:03}", i), &format!("new{}", i))?;
+    }
+    store.compact()?;
+    assert!(!files_with_extension(temp_dir.path(), "retired").is_empty());
+
+    for i in 0..200 {
+        assert_eq!(
+            snapshot.get(&format!("key{:03}", i))?,
+            Some(format!("old{}", i))
+        );
+        assert_eq!(
+            store.get(&format!("key{:03}", i))?,
+            Some(format!("new{}", i))
+        );
+    }
+    let pairs: Vec<(String, String)> = snapshot
+        .scan("key190", None, None)?
+        .collect::<Result<_>>()?;
+    let expected: Vec<(String, String)> = (190..200)
+        .map(|i| (format!("key{:03}", i), format!("old{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    drop(snapshot);
+    assert!(files_with_extension(temp_dir.path(), "retired").is_empty());
+
+    // one left behind by a process that exited with a snapshot open
+    drop(store);
+    fs::write(temp_dir.path().join("1.retired"), b"stale")?;
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(files_with_extension(temp_dir.path(), "retired").is_empty());
+    assert_eq!(store.get("key000")?, Some("new0".to_owned()));
+    Ok(())
+}
+
+// Tables merged away while a snapshot reads them should only be removed once it is dropped
+#[test]
+fn compaction_keeps_pinned_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = LsmOptions::new()
+        .memtable_size(1024)
+        .table_size(2048)
+        .level0_tables(2)
+        .level_size(4096, 4);
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..300 {
+        engine.set(&format!("key{:03}", i), &format!("old{}", i))?;
+    }
+    let snapshot = engine.snapshot()?;
+    for i in 0..300 {
+        engine.set(&format!("key{:03}", i), &format!("new{}", i))?;
+    }
+    engine.compact()?;
+    let pinned_tables = files_with_extension(temp_dir.path(), "sst").len();
+
+    for i in 0..300 {
+        assert_eq!(
+            snapshot.get(&format!("key{:03}", i))?,
+            Some(format!("old{}", i))
+        );
+        assert_eq!(
+            engine.get(&format!("key{:03}", i))?,
+            Some(format!("new{}", i))
+        );
+    }
+    assert_eq!(snapshot.scan("", None, None)?.count(), 300);
+
+    drop(snapshot);
+    assert!(files_with_extension(temp_dir.path(), "sst").len() < pinned_tables);
+    assert_eq!(engine.get("key000")?, Some("new0".to_owned()));
+    Ok(())
+}